@@ -11,13 +11,13 @@ use rsget_lib::{
     utils::error::{RsgetError, StreamError, StreamResult},
     Status, Streamable,
 };
-use stream_lib::Event;
+use stream_lib::{DownloadStream, Event, RenditionSelector, SubtitleFormat, VariantSelector};
 use tokio::{
     fs::File,
     io::{AsyncWriteExt as _, BufWriter},
     runtime::Runtime,
 };
-use tracing::warn;
+use tracing::{debug, warn};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -32,6 +32,20 @@ struct Opt {
     filename: Option<String>,
     #[arg(short = 'n', long = "network-play")]
     network_play: bool,
+    /// Quality to record: best, worst, 720p, 720p60 or a rendition name.
+    #[arg(short = 'q', long = "quality", default_value = "best")]
+    quality: VariantSelector,
+    /// Also record a separate audio track next to the video: default, a
+    /// language like da or en-US, or a rendition name.
+    #[arg(short = 'a', long = "audio")]
+    audio: Option<RenditionSelector>,
+    /// Also record subtitles next to the video: default, a language like da
+    /// or a rendition name.
+    #[arg(short = 's', long = "subtitles")]
+    subtitles: Option<RenditionSelector>,
+    /// Format of the subtitle file, vtt or srt.
+    #[arg(long = "subtitle-format", default_value = "vtt")]
+    subtitle_format: SubtitleFormat,
     url: String,
 }
 
@@ -48,7 +62,7 @@ async fn async_main() -> StreamResult<()> {
     let opt = Opt::parse();
     let url = opt.url;
     let parsed_url = Url::parse(&url).unwrap();
-    let stream: Box<dyn Streamable + Send> = rsget_lib::utils::sites::get_site(&url).await?;
+    let stream: Box<dyn Streamable + Send + Sync> = rsget_lib::utils::sites::get_site(&url).await?;
 
     match stream.is_online().await? {
         Status::Offline => return Err(StreamError::Rsget(RsgetError::Offline)),
@@ -100,7 +114,7 @@ async fn async_main() -> StreamResult<()> {
     }
 
     if opt.filename.as_ref().is_some_and(|f| f == "-") {
-        stream_to_stdout(stream).await?;
+        stream_to_stdout(stream, opt.quality).await?;
         return Ok(());
     }
 
@@ -112,7 +126,43 @@ async fn async_main() -> StreamResult<()> {
     let path = Path::new(&full_path);
     let mut file = BufWriter::new(File::create(path).await?);
 
-    let mut dl = stream.get_stream().await?;
+    // The recording starts before the sidecars, which can not stop it.
+    let mut dl = stream.get_stream_variant(opt.quality.clone()).await?;
+
+    let audio = match opt.audio {
+        Some(audio) => match stream.get_audio_stream(opt.quality.clone(), audio).await {
+            Ok(Some(dl)) => Some(tokio::spawn(write_sidecar(dl, audio_path(path), "audio"))),
+            Ok(None) => {
+                println!("No separate audio was found");
+                None
+            }
+            Err(e) => {
+                warn!("Could not start the audio download: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let subtitles = match opt.subtitles {
+        Some(subtitles) => match stream
+            .get_subtitles_stream(opt.quality, subtitles, opt.subtitle_format)
+            .await
+        {
+            Ok(Some(dl)) => {
+                let path = path.with_extension(opt.subtitle_format.extension());
+                Some(tokio::spawn(write_sidecar(dl, path, "subtitle")))
+            }
+            Ok(None) => {
+                println!("No subtitles were found");
+                None
+            }
+            Err(e) => {
+                warn!("Could not start the subtitle download: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
 
     let spinsty = indicatif::ProgressStyle::default_spinner()
         .template(
@@ -122,6 +172,7 @@ async fn async_main() -> StreamResult<()> {
     let spinner = indicatif::ProgressBar::new_spinner().with_style(spinsty);
 
     let mut size = 0;
+    let mut missing = Duration::ZERO;
 
     while let Some(event) = dl.next().await {
         match event {
@@ -130,6 +181,38 @@ async fn async_main() -> StreamResult<()> {
                 size += bytes.len();
                 file.write_all_buf(&mut bytes).await?;
             }
+            Event::SegmentStart { info } if info.discontinuity => {
+                debug!("Discontinuity at segment {}", info.sequence);
+            }
+            Event::SegmentStart { .. } | Event::SegmentEnd { .. } => {}
+            Event::Dropped { segments, bytes } => {
+                warn!(
+                    "Dropped {} segments ({} bytes) as the output could not keep up",
+                    segments, bytes
+                );
+            }
+            Event::Gap {
+                missing_segments,
+                missing_duration,
+            } => {
+                warn!(
+                    "Missed {} segments (about {:?}) that left the playlist before they could be downloaded",
+                    missing_segments, missing_duration
+                );
+                missing += missing_duration;
+            }
+            Event::SegmentFailed { url, error } => {
+                warn!("Skipped segment {}: {}", url, error);
+            }
+            Event::ContentLength { total } => {
+                let barsty = indicatif::ProgressStyle::default_bar()
+                    .template(
+                        "{bar:40.cyan/blue} {bytes}/{total_bytes} ({percent}%), {.blue}ETA: {eta}",
+                    )
+                    .unwrap();
+                spinner.set_style(barsty);
+                spinner.set_length(total);
+            }
             Event::End => {
                 eprintln!("End received");
                 break;
@@ -138,25 +221,66 @@ async fn async_main() -> StreamResult<()> {
                 eprintln!("Error occured when downloading stream: {}", error);
                 break;
             }
+            _ => {}
         }
     }
 
     println!("Downloaded: {} MB", size as f64 / 1000.0 / 1000.0);
+    for (what, sidecar) in [("audio", audio), ("subtitles", subtitles)] {
+        let Some(sidecar) = sidecar else {
+            continue;
+        };
+        match sidecar.await {
+            Ok(Ok(0)) => println!("No separate {} was found, nothing was written", what),
+            Ok(Ok(size)) => println!("Downloaded {}: {} MB", what, size as f64 / 1000.0 / 1000.0),
+            Ok(Err(e)) => eprintln!("Error occured when downloading {}: {}", what, e),
+            Err(e) => eprintln!("The {} download stopped: {}", what, e),
+        }
+    }
+    if !missing.is_zero() {
+        println!(
+            "The recording is incomplete, about {:?} of the stream is missing",
+            missing
+        );
+    }
     Ok(())
 }
 
-async fn stream_to_stdout<S>(stream: Box<S>) -> Result<(), StreamError>
+async fn stream_to_stdout<S>(stream: Box<S>, quality: VariantSelector) -> Result<(), StreamError>
 where
-    S: Streamable + Send + ?Sized,
+    S: Streamable + Send + Sync + ?Sized,
 {
     let mut file = BufWriter::new(tokio::io::stdout());
-    let mut dl = stream.get_stream().await?;
+    let mut dl = stream.get_stream_variant(quality).await?;
 
     while let Some(event) = dl.next().await {
         match event {
             Event::Bytes { mut bytes } => {
                 file.write_all_buf(&mut bytes).await?;
             }
+            Event::SegmentStart { info } if info.discontinuity => {
+                debug!("Discontinuity at segment {}", info.sequence);
+            }
+            Event::SegmentStart { .. } | Event::SegmentEnd { .. } => {}
+            Event::Dropped { segments, bytes } => {
+                warn!(
+                    "Dropped {} segments ({} bytes) as the output could not keep up",
+                    segments, bytes
+                );
+            }
+            Event::Gap {
+                missing_segments,
+                missing_duration,
+            } => {
+                warn!(
+                    "Missed {} segments (about {:?}) that left the playlist before they could be downloaded",
+                    missing_segments, missing_duration
+                );
+            }
+            Event::SegmentFailed { url, error } => {
+                warn!("Skipped segment {}: {}", url, error);
+            }
+            Event::ContentLength { .. } => {}
             Event::End => {
                 eprintln!("End received");
                 break;
@@ -165,17 +289,69 @@ where
                 eprintln!("Error occured when downloading stream: {}", error);
                 break;
             }
+            _ => {}
         }
     }
 
     Ok(())
 }
 
+/// The audio track is saved next to the video as `name.audio.ext`.
+fn audio_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(ext) => path.with_extension(format!("audio.{}", ext.to_string_lossy())),
+        None => path.with_extension("audio"),
+    }
+}
+
+/// Writes a track that is recorded next to the video, such as audio or
+/// subtitles, to `path`.
+///
+/// The file is only created once there is something to write, so no empty
+/// file is left when the stream has no such track.
+async fn write_sidecar(
+    mut dl: DownloadStream,
+    path: PathBuf,
+    what: &'static str,
+) -> Result<usize, StreamError> {
+    let mut file = None;
+    let mut size = 0;
+
+    while let Some(event) = dl.next().await {
+        match event {
+            Event::Bytes { mut bytes } => {
+                let file = match &mut file {
+                    Some(file) => file,
+                    None => file.insert(BufWriter::new(File::create(&path).await?)),
+                };
+                size += bytes.len();
+                file.write_all_buf(&mut bytes).await?;
+            }
+            Event::Gap {
+                missing_segments, ..
+            } => {
+                warn!("Missed {} {} segments", missing_segments, what);
+            }
+            Event::SegmentFailed { url, error } => {
+                warn!("Skipped {} segment {}: {}", what, url, error);
+            }
+            Event::Error { error } => return Err(error.into()),
+            Event::End => break,
+            _ => {}
+        }
+    }
+
+    if let Some(mut file) = file {
+        file.flush().await?;
+    }
+    Ok(size)
+}
+
 /*
 #[allow(clippy::boxed_local)]
 async fn stream_network<S>(stream: Box<S>) -> Result<u64, StreamError>
 where
-    S: Streamable + Send + ?Sized,
+    S: Streamable + Send + Sync + ?Sized,
 {
     use tokio::net::TcpListener;
     let mut listener = TcpListener::bind("127.0.0.1:61337").await?;
@@ -8,7 +8,7 @@ use crate::utils::error::StreamResult;
 
 use std::boxed::Box;
 
-use stream_lib::DownloadStream;
+use stream_lib::{DownloadStream, RenditionSelector, SubtitleFormat, VariantSelector};
 
 use async_trait::async_trait;
 
@@ -36,6 +36,30 @@ pub trait Streamable {
     async fn is_online(&self) -> StreamResult<Status>;
     /// Gets the url of the stream
     async fn get_stream(&self) -> StreamResult<DownloadStream>;
+    /// Gets the stream in the quality picked by `selector`, sites that only
+    /// offer a single quality ignore it.
+    async fn get_stream_variant(&self, _selector: VariantSelector) -> StreamResult<DownloadStream> {
+        self.get_stream().await
+    }
+    /// Gets the alternative audio track picked by `audio` for the quality
+    /// picked by `selector`, `None` if the site has no separate audio.
+    async fn get_audio_stream(
+        &self,
+        _selector: VariantSelector,
+        _audio: RenditionSelector,
+    ) -> StreamResult<Option<DownloadStream>> {
+        Ok(None)
+    }
+    /// Gets the subtitles picked by `subtitles` as a single file in
+    /// `format`, `None` if the site has no subtitles.
+    async fn get_subtitles_stream(
+        &self,
+        _selector: VariantSelector,
+        _subtitles: RenditionSelector,
+        _format: SubtitleFormat,
+    ) -> StreamResult<Option<DownloadStream>> {
+        Ok(None)
+    }
     /// Returns what extension the stream should be
     async fn get_ext(&self) -> StreamResult<String>;
     /// Gets the default name of the stream
@@ -9,6 +9,7 @@ use crate::utils::error::{RsgetError, StreamError, StreamResult};
 use chrono::prelude::*;
 
 use reqwest::header::REFERER;
+use stream_lib::{RequestRefresh, SegmentInfo, StreamBuilder};
 
 use async_trait::async_trait;
 
@@ -218,14 +219,39 @@ impl Streamable for Afreeca {
         debug!("view_url: {}", self.stream_info.view_url);
         let url = format!("{}?aid={}", self.stream_info.view_url, self.hls_key);
 
-        Ok(stream_lib::download_hls(
+        // The aid key expires, a new one is fetched when the playlist is
+        // rejected.
+        let afreeca = self.clone();
+        let refresh = RequestRefresh::new(move || {
+            let afreeca = afreeca.clone();
+            async move {
+                let hls_key = get_hls_key(
+                    afreeca.client.clone(),
+                    afreeca.url.clone(),
+                    afreeca.room_id.clone(),
+                    afreeca.bno.clone(),
+                )
+                .await
+                .map_err(|e| warn!("[Afreeca] Could not get a new hls key: {}", e))
+                .ok()?;
+                afreeca
+                    .client
+                    .get(format!("{}?aid={}", afreeca.stream_info.view_url, hls_key))
+                    .header(REFERER, afreeca.url.clone())
+                    .build()
+                    .ok()
+            }
+        });
+        Ok(StreamBuilder::new(
             self.client.clone(),
             self.client
                 .get(url)
                 .header(REFERER, self.url.clone())
                 .build()?,
-            Some(|e: &str| -> bool { !e.contains("preloading") }),
-        ))
+        )
+        .filter(Box::new(|s: &SegmentInfo| !s.uri.contains("preloading")))
+        .refresh(refresh)
+        .hls())
     }
 
     async fn get_ext(&self) -> StreamResult<String> {
@@ -1,6 +1,7 @@
 use crate::{Status, Streamable};
 use regex::Regex;
-use stream_lib::DownloadStream;
+use stream_lib::{DownloadStream, RequestRefresh, StreamBuilder};
+use tracing::warn;
 
 use crate::utils::error::RsgetError;
 use crate::utils::error::StreamError;
@@ -46,6 +47,22 @@ pub struct Bilibili {
     durl_list: Vec<Durl>,
 }
 
+/// Gets the urls of the live stream, they are only valid for a while.
+async fn play_urls(client: &reqwest::Client, room_id: &str) -> StreamResult<Vec<Durl>> {
+    Ok(client
+        .get("https://api.live.bilibili.com/room/v1/Room/playUrl")
+        .query(&[("cid", room_id), ("quality", "0"), ("platform", "web")])
+        .header("User-Agent", USER_AGENT)
+        .header("Accept", "*/*")
+        .header("Accept-Language", "en-US,en;q=0.5")
+        .send()
+        .await?
+        .json::<PlayUrlHead>()
+        .await?
+        .data
+        .durl)
+}
+
 #[async_trait]
 impl Streamable for Bilibili {
     async fn new(url: String) -> StreamResult<Box<Bilibili>> {
@@ -76,18 +93,7 @@ impl Streamable for Bilibili {
             return Err(RsgetError::Offline.into());
         }
 
-        let durls = client
-            .get("https://api.live.bilibili.com/room/v1/Room/playUrl")
-            .query(&[("cid", &cap[1]), ("quality", "0"), ("platform", "web")])
-            .header("User-Agent", USER_AGENT)
-            .header("Accept", "*/*")
-            .header("Accept-Language", "en-US,en;q=0.5")
-            .send()
-            .await?
-            .json::<PlayUrlHead>()
-            .await?
-            .data
-            .durl;
+        let durls = play_urls(&client, &room_id).await?;
 
         Ok(Box::new(Bilibili {
             client,
@@ -114,13 +120,32 @@ impl Streamable for Bilibili {
     }
 
     async fn get_stream(&self) -> StreamResult<DownloadStream> {
-        Ok(stream_lib::download_chunked(
+        let client = self.client.clone();
+        let room_id = self.room_id.clone();
+        let refresh = RequestRefresh::new(move || {
+            let client = client.clone();
+            let room_id = room_id.clone();
+            async move {
+                let durls = play_urls(&client, &room_id)
+                    .await
+                    .map_err(|e| warn!("[Bilibili] Could not get a new stream url: {}", e))
+                    .ok()?;
+                client
+                    .get(&durls.first()?.url)
+                    .header("User-Agent", USER_AGENT)
+                    .build()
+                    .ok()
+            }
+        });
+        Ok(StreamBuilder::new(
             self.client.clone(),
             self.client
                 .get(&self.durl_list[0].url)
                 .header("User-Agent", USER_AGENT)
                 .build()?,
-        ))
+        )
+        .refresh(refresh)
+        .live_chunked())
     }
 
     async fn get_ext(&self) -> StreamResult<String> {
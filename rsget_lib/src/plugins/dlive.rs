@@ -2,7 +2,7 @@
 
 use crate::{Status, Streamable};
 use regex::Regex;
-use stream_lib::DownloadStream;
+use stream_lib::{DownloadStream, StreamBuilder, VariantSelector};
 use tracing::debug;
 
 use crate::utils::error::RsgetError;
@@ -82,6 +82,10 @@ impl Streamable for DLive {
     }
 
     async fn get_stream(&self) -> StreamResult<DownloadStream> {
+        self.get_stream_variant(VariantSelector::Best).await
+    }
+
+    async fn get_stream_variant(&self, selector: VariantSelector) -> StreamResult<DownloadStream> {
         let url = format!(
             "https://live.prd.dlive.tv/hls/live/{}.m3u8",
             &self.apollo_state["username"]
@@ -90,12 +94,11 @@ impl Streamable for DLive {
                 .trim_start_matches("%22")
                 .trim_end_matches("%22")
         );
-        Ok(stream_lib::download_hls_named(
-            self.client.clone(),
-            self.client.get(url).build()?,
-            String::from("src"),
-            None,
-        ))
+        Ok(
+            StreamBuilder::new(self.client.clone(), self.client.get(url).build()?)
+                .variant(selector)
+                .hls(),
+        )
     }
 
     async fn get_ext(&self) -> StreamResult<String> {
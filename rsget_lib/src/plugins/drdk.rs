@@ -4,8 +4,11 @@ use std::collections::BTreeMap;
 
 use async_trait::async_trait;
 use chrono::{DateTime, Datelike, Local, Timelike};
+use hls_m3u8::{types::MediaType, MasterPlaylist};
 use regex::Regex;
-use stream_lib::DownloadStream;
+use stream_lib::{
+    DownloadStream, RenditionSelector, StreamBuilder, SubtitleFormat, VariantSelector,
+};
 
 use crate::{
     utils::error::{RsgetError, StreamError, StreamResult},
@@ -68,12 +71,63 @@ impl Streamable for Drdk {
         Ok(Status::Unknown)
     }
     async fn get_stream(&self) -> StreamResult<DownloadStream> {
+        self.get_stream_variant(VariantSelector::Best).await
+    }
+    async fn get_stream_variant(&self, selector: VariantSelector) -> StreamResult<DownloadStream> {
+        let http = reqwest::Client::new();
+        let request = http.get(&self.hls_url).build()?;
+        Ok(StreamBuilder::new(http, request).variant(selector).hls())
+    }
+    async fn get_audio_stream(
+        &self,
+        selector: VariantSelector,
+        audio: RenditionSelector,
+    ) -> StreamResult<Option<DownloadStream>> {
+        let http = reqwest::Client::new();
+        // Most channels have the audio in the video segments.
+        let master = http
+            .get(&self.hls_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let separate = MasterPlaylist::try_from(master.as_str())?
+            .media
+            .iter()
+            .any(|m| m.media_type == MediaType::Audio && m.uri().is_some());
+        if !separate {
+            return Ok(None);
+        }
+
+        let request = http.get(&self.hls_url).build()?;
+        Ok(Some(
+            StreamBuilder::new(http, request)
+                .variant(selector)
+                .hls_audio(audio),
+        ))
+    }
+    async fn get_subtitles_stream(
+        &self,
+        selector: VariantSelector,
+        subtitles: RenditionSelector,
+        format: SubtitleFormat,
+    ) -> StreamResult<Option<DownloadStream>> {
         let http = reqwest::Client::new();
         let request = http.get(&self.hls_url).build()?;
-        Ok(stream_lib::download_hls_master_first(http, request, None))
+        Ok(Some(
+            StreamBuilder::new(http, request)
+                .variant(selector)
+                .hls_subtitles(subtitles, format),
+        ))
     }
     async fn get_ext(&self) -> StreamResult<String> {
-        Ok("ts".to_owned())
+        let http = reqwest::Client::new();
+        let request = http.get(&self.hls_url).build()?;
+        let ext = stream_lib::hls_extension(http, request)
+            .await
+            .unwrap_or("ts");
+        Ok(ext.to_owned())
     }
     async fn get_default_name(&self) -> StreamResult<String> {
         let local: DateTime<Local> = Local::now();
@@ -12,7 +12,8 @@ use std::{
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use stream_lib::DownloadStream;
+use stream_lib::{DownloadStream, RequestRefresh, StreamBuilder, VariantSelector};
+use tracing::warn;
 
 use crate::utils::error::RsgetError;
 use crate::utils::error::StreamError;
@@ -67,6 +68,32 @@ pub struct Twitch {
     access_token: Option<String>,
 }
 
+impl Twitch {
+    /// Gets a fresh access token and builds the usher url of the master playlist.
+    async fn playlist_url(&self) -> StreamResult<String> {
+        let auth_endpoint = format!(
+            "https://api.twitch.tv/api/channels/{}/access_token?client_id={}",
+            self.username, TWITCH_CLIENT_ID_PRIVATE
+        );
+        let auth_res = self
+            .client
+            .get(auth_endpoint.as_str())
+            .send()
+            .await?
+            .text()
+            .await?;
+        let acs: AccessToken = serde_json::from_str(auth_res.as_str())?;
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut rng = SmallRng::seed_from_u64(time);
+        Ok(format!("https://usher.ttvnw.net/api/channel/hls/{}.m3u8?player=twitchweb&token={}&sig={}&allow_audio_only=true&allow_source=true&type=any&p={}",
+                   self.username, acs.token, acs.sig, rng.gen_range(1..=999_999)))
+    }
+}
+
 #[async_trait]
 impl Streamable for Twitch {
     async fn new(url: String) -> StreamResult<Box<Twitch>> {
@@ -81,10 +108,7 @@ impl Streamable for Twitch {
             Err(_) => String::from(TWITCH_CLIENT_ID),
         };
 
-        let access_token = match env::var("RSGET_TWITCH_ACCESS_TOKEN") {
-            Ok(val) => Some(val),
-            Err(_) => None,
-        };
+        let access_token = env::var("RSGET_TWITCH_ACCESS_TOKEN").ok();
 
         let twitch = Twitch {
             client,
@@ -148,46 +172,50 @@ impl Streamable for Twitch {
         }
     }
     async fn get_stream(&self) -> StreamResult<DownloadStream> {
-        let auth_endpoint = format!(
-            "https://api.twitch.tv/api/channels/{}/access_token?client_id={}",
-            self.username, TWITCH_CLIENT_ID_PRIVATE
-        );
-        let auth_res = self
-            .client
-            .get(auth_endpoint.as_str())
-            .send()
-            .await?
-            .text()
-            .await?;
-        let acs: AccessToken = serde_json::from_str(auth_res.as_str())?;
-
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let mut rng = SmallRng::seed_from_u64(time);
-        let playlist_url = format!("https://usher.ttvnw.net/api/channel/hls/{}.m3u8?player=twitchweb&token={}&sig={}&allow_audio_only=true&allow_source=true&type=any&p={}",
-                                    self.username, acs.token, acs.sig, rng.gen_range(1..=999_999));
-
-        let playlist_res = self
-            .client
-            .get(playlist_url.as_str())
-            .send()
-            .await?
-            .text()
-            .await?;
-        let playlist = MasterPlaylist::try_from(playlist_res.as_str())?;
-        let qu_name = playlist.media.first().unwrap().name();
-
-        Ok(stream_lib::download_hls_named(
-            self.client.clone(),
-            self.client.get(&playlist_url).build()?,
-            String::from(qu_name.as_ref()),
-            None,
-        ))
+        self.get_stream_variant(VariantSelector::Best).await
+    }
+    async fn get_stream_variant(&self, selector: VariantSelector) -> StreamResult<DownloadStream> {
+        let playlist_url = self.playlist_url().await?;
+
+        // The access token in the usher url expires, a new one is fetched
+        // when twitch starts rejecting the playlist.
+        let twitch = self.clone();
+        let refresh = RequestRefresh::new(move || {
+            let twitch = twitch.clone();
+            async move {
+                let url = twitch
+                    .playlist_url()
+                    .await
+                    .map_err(|e| warn!("[Twitch] Could not get a new access token: {}", e))
+                    .ok()?;
+                twitch.client.get(&url).build().ok()
+            }
+        });
+        Ok(
+            StreamBuilder::new(self.client.clone(), self.client.get(&playlist_url).build()?)
+                .variant(selector)
+                .refresh(refresh)
+                .hls(),
+        )
     }
     async fn get_ext(&self) -> StreamResult<String> {
-        Ok(String::from("mp4"))
+        // Probe the variant `get_stream` records, a failed probe should not
+        // stop the recording from starting.
+        let ext = match self.playlist_url().await {
+            Ok(playlist_url) => match self.client.get(&playlist_url).build() {
+                Ok(request) => StreamBuilder::new(self.client.clone(), request)
+                    .variant(VariantSelector::Best)
+                    .hls_extension()
+                    .await
+                    .ok(),
+                Err(_) => None,
+            },
+            Err(e) => {
+                warn!("[Twitch] Could not probe the stream: {}", e);
+                None
+            }
+        };
+        Ok(String::from(ext.unwrap_or("mp4")))
     }
 
     async fn get_default_name(&self) -> StreamResult<String> {
@@ -5,7 +5,7 @@ use regex::Regex;
 
 use async_trait::async_trait;
 
-use stream_lib::DownloadStream;
+use stream_lib::{DownloadStream, StreamBuilder};
 
 use crate::utils::error::StreamResult;
 
@@ -22,7 +22,7 @@ pub struct Vlive {
     // TODO FOR ERK: This field is currently unused. This is due to Rsgets design being too focused on making plugin
     // implementation easier for developers, but at the expense of more "native" per site support. To access the m3u8
     // files and the .ts files from vlive you need to provide a session key for the requests. If you look at where I73
-    
+
     // define VideoInfo, theres is a list field `streams`. Each of these streams has field "key" which has a name and
     // a value, which must be appended as a url parameter to every request to that stream. For example:
     // {
@@ -72,7 +72,7 @@ impl Streamable for Vlive {
             .ok_or_else(|| StreamError::Rsget(RsgetError::new("No capture found")))?[1]
             .to_string();
 
-        let page_req = http.get(&format!("https://global.apis.naver.com/rmcnmv/rmcnmv/vod_play_videoInfo.json?key={}&videoId={}", key, id)).send().await?;
+        let page_req = http.get(format!("https://global.apis.naver.com/rmcnmv/rmcnmv/vod_play_videoInfo.json?key={}&videoId={}", key, id)).send().await?;
 
         // all these structs are quite excessive for what we actually need but i want to be ready for the "Quality Update"
         // Currently this backend just chooses the video with the highest file size, aka most likely to be highest quality
@@ -169,10 +169,7 @@ impl Streamable for Vlive {
             .video_url
             .clone()
             .ok_or_else(|| StreamError::Rsget(RsgetError::new("No videos available")))?;
-        Ok(stream_lib::download_chunked(
-            self.http.clone(),
-            self.http.get(url).build()?,
-        ))
+        Ok(StreamBuilder::new(self.http.clone(), self.http.get(url).build()?).chunked())
     }
 
     async fn get_ext(&self) -> StreamResult<String> {
@@ -12,7 +12,7 @@ use crate::utils::error::StreamResult;
 use crate::Streamable;
 use regex::Regex;
 
-pub async fn get_site(input: &str) -> StreamResult<Box<dyn Streamable + Send>> {
+pub async fn get_site(input: &str) -> StreamResult<Box<dyn Streamable + Send + Sync>> {
     match _get_site(input).await {
         Ok(s) => Ok(s),
         Err(StreamError::Rsget(_)) => {
@@ -24,7 +24,7 @@ pub async fn get_site(input: &str) -> StreamResult<Box<dyn Streamable + Send>> {
     }
 }
 
-async fn _get_site(input: &str) -> StreamResult<Box<dyn Streamable + Send>> {
+async fn _get_site(input: &str) -> StreamResult<Box<dyn Streamable + Send + Sync>> {
     let re_drdk: Regex = Regex::new(r"^(?:https?://)?(?:www\.)?dr\.dk/drtv/kanal/[a-zA-Z0-9-_]+")?;
     let re_afreeca: Regex = Regex::new(
         r"^(?:https?://)?(?:www\.)?(?:play\.)?afreecatv.com/[a-zA-Z0-9]+/?(?:/[0-9]+)?",
@@ -0,0 +1,57 @@
+use futures_util::StreamExt as _;
+use reqwest::Client;
+use stream_lib::{Event, StreamBuilder};
+use tokio::io::AsyncWriteExt;
+
+/// Write buffer
+pub const WRITE_SIZE: usize = 131_072;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+    let args = std::env::args().collect::<Vec<_>>();
+    let url = args
+        .get(1)
+        .expect("Pass a manifest url as the first argument");
+    let quality = args.get(2).map_or("best", |q| q.as_str()).parse()?;
+
+    let http = Client::new();
+    let req = http.get(url).build()?;
+    let mut dl = StreamBuilder::new(http, req).variant(quality).dash();
+
+    let mut file = tokio::io::BufWriter::with_capacity(
+        WRITE_SIZE,
+        tokio::fs::File::create("./test.mp4").await?,
+    );
+
+    while let Some(event) = dl.next().await {
+        match event {
+            Event::Bytes { bytes } => {
+                file.write_all(&bytes).await?;
+            }
+            Event::SegmentStart { info } if info.discontinuity => {
+                eprintln!("New period at segment {}", info.sequence);
+            }
+            Event::SegmentStart { .. } | Event::SegmentEnd { .. } => {}
+            Event::Dropped { segments, .. } => {
+                eprintln!("Dropped {} segments", segments);
+            }
+            Event::Gap {
+                missing_segments, ..
+            } => {
+                eprintln!("Missed {} segments", missing_segments);
+            }
+            Event::SegmentFailed { url, error } => {
+                eprintln!("Skipped segment {}: {}", url, error);
+            }
+            Event::ContentLength { .. } => {}
+            Event::End => break,
+            Event::Error { error } => {
+                eprintln!("Encounted error: {}", error);
+                break;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use futures_util::StreamExt as _;
 use reqwest::Client;
-use stream_lib::Event;
+use stream_lib::{Event, StreamBuilder};
 use tokio::io::AsyncWriteExt;
 
 /// Write buffer
@@ -14,7 +14,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let http = Client::new();
     let req = http.get(url).build()?;
-    let mut dl = stream_lib::download_hls(http, req, None);
+    let mut dl = StreamBuilder::new(http, req).hls();
 
     let mut file = tokio::io::BufWriter::with_capacity(
         WRITE_SIZE,
@@ -26,11 +26,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Event::Bytes { bytes } => {
                 file.write_all(&bytes).await?;
             }
+            Event::SegmentStart { info } if info.discontinuity => {
+                eprintln!("Discontinuity at segment {}", info.sequence);
+            }
+            Event::SegmentStart { .. } | Event::SegmentEnd { .. } => {}
+            Event::Dropped { segments, .. } => {
+                eprintln!("Dropped {} segments", segments);
+            }
+            Event::Gap {
+                missing_segments, ..
+            } => {
+                eprintln!("Missed {} segments", missing_segments);
+            }
+            Event::SegmentFailed { url, error } => {
+                eprintln!("Skipped segment {}: {}", url, error);
+            }
+            Event::ContentLength { .. } => {}
             Event::End => break,
             Event::Error { error } => {
                 eprintln!("Encounted error: {}", error);
                 break;
             }
+            _ => {}
         }
     }
     Ok(())
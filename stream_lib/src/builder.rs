@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, Request,
+};
+
+use crate::{
+    config::DownloadConfig,
+    download_stream::{BufferSize, DownloadStream, Overflow},
+    hls::{SegmentFilter, SubtitleFormat},
+    rate::RateLimiter,
+    refresh::RequestRefresh,
+    retry::RetryPolicy,
+    variant::{RenditionSelector, VariantSelector},
+    Error,
+};
+
+/// Sets up a download and starts it with one of the methods for the kind of
+/// stream, like [`StreamBuilder::hls`] or [`StreamBuilder::chunked`].
+///
+/// Everything that is not set uses the defaults of [`DownloadConfig`].
+pub struct StreamBuilder {
+    http: Client,
+    request: Request,
+    config: DownloadConfig,
+    variant: Option<VariantSelector>,
+    filter: Option<SegmentFilter>,
+}
+
+impl StreamBuilder {
+    pub fn new(http: Client, request: Request) -> Self {
+        StreamBuilder {
+            http,
+            request,
+            config: DownloadConfig::default(),
+            variant: None,
+            filter: None,
+        }
+    }
+
+    /// Replaces all the settings at once.
+    pub fn config(mut self, config: DownloadConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds a header to the request, it is also sent with every playlist,
+    /// segment and key request.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.request.headers_mut().insert(name, value);
+        self
+    }
+
+    /// Adds all of `headers` to the request, see [`StreamBuilder::header`].
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.request.headers_mut().extend(headers);
+        self
+    }
+
+    /// Only HLS segments the filter returns `true` for are downloaded.
+    ///
+    /// For example if you want filter preloading segments use:
+    /// `Box::new(|s| !s.uri.contains("preloading"))`.
+    pub fn filter(mut self, filter: SegmentFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Picks the variant of a HLS master playlist or the representation of
+    /// a DASH manifest. Without it [`StreamBuilder::hls`] expects a media
+    /// playlist and the other methods take the best one.
+    pub fn variant(mut self, selector: VariantSelector) -> Self {
+        self.variant = Some(selector);
+        self
+    }
+
+    /// See [`DownloadConfig::buffer`].
+    pub fn buffer(mut self, buffer: BufferSize) -> Self {
+        self.config.buffer = buffer;
+        self
+    }
+
+    /// See [`DownloadConfig::overflow`].
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.config.overflow = overflow;
+        self
+    }
+
+    /// See [`DownloadConfig::concurrency`].
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.config.concurrency = concurrency;
+        self
+    }
+
+    /// See [`DownloadConfig::retry`].
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    /// See [`DownloadConfig::low_latency`].
+    pub fn low_latency(mut self, low_latency: bool) -> Self {
+        self.config.low_latency = low_latency;
+        self
+    }
+
+    /// See [`DownloadConfig::refresh`].
+    pub fn refresh(mut self, refresh: RequestRefresh) -> Self {
+        self.config.refresh = Some(refresh);
+        self
+    }
+
+    /// See [`DownloadConfig::rate_limit`].
+    pub fn rate_limit(mut self, rate_limit: RateLimiter) -> Self {
+        self.config.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// See [`DownloadConfig::playlist_timeout`].
+    pub fn playlist_timeout(mut self, timeout: Duration) -> Self {
+        self.config.playlist_timeout = timeout;
+        self
+    }
+
+    /// See [`DownloadConfig::segment_timeout`].
+    pub fn segment_timeout(mut self, timeout: Duration) -> Self {
+        self.config.segment_timeout = timeout;
+        self
+    }
+
+    /// See [`DownloadConfig::chunked_timeout`].
+    pub fn chunked_timeout(mut self, timeout: Duration) -> Self {
+        self.config.chunked_timeout = timeout;
+        self
+    }
+
+    /// See [`DownloadConfig::live_chunked_timeout`].
+    pub fn live_chunked_timeout(mut self, timeout: Duration) -> Self {
+        self.config.live_chunked_timeout = timeout;
+        self
+    }
+
+    /// See [`DownloadConfig::playlist_retries`].
+    pub fn playlist_retries(mut self, retries: usize) -> Self {
+        self.config.playlist_retries = retries;
+        self
+    }
+
+    /// See [`DownloadConfig::end_timeout`].
+    pub fn end_timeout(mut self, timeout: Duration) -> Self {
+        self.config.end_timeout = timeout;
+        self
+    }
+
+    /// See [`DownloadConfig::allowable_excess_duration`].
+    pub fn allowable_excess_duration(mut self, excess: Duration) -> Self {
+        self.config.allowable_excess_duration = excess;
+        self
+    }
+
+    /// See [`DownloadConfig::reload_interval`].
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.config.reload_interval = Some(interval);
+        self
+    }
+
+    /// Guesses the file extension of the HLS stream [`StreamBuilder::hls`]
+    /// would download, see [`DownloadConfig::hls_extension`].
+    pub async fn hls_extension(self) -> Result<&'static str, Error> {
+        self.config
+            .hls_extension(self.http, self.request, self.variant.as_ref())
+            .await
+    }
+
+    /// Downloads a HLS stream, the variant is picked from the master
+    /// playlist if [`StreamBuilder::variant`] was set.
+    pub fn hls(self) -> DownloadStream {
+        match self.variant {
+            Some(selector) => {
+                self.config
+                    .download_hls_variant(self.http, self.request, selector, self.filter)
+            }
+            None => self
+                .config
+                .download_hls(self.http, self.request, self.filter),
+        }
+    }
+
+    /// Downloads an alternative audio rendition of the variant, see
+    /// [`DownloadConfig::download_hls_audio`].
+    pub fn hls_audio(self, audio: RenditionSelector) -> DownloadStream {
+        let selector = self.variant.unwrap_or(VariantSelector::Best);
+        self.config
+            .download_hls_audio(self.http, self.request, selector, audio, self.filter)
+    }
+
+    /// Downloads a subtitle rendition of the variant as a single file, timed
+    /// to match a recording of the variant started next to it.
+    /// The filter is not used, every cue is kept.
+    pub fn hls_subtitles(
+        self,
+        subtitles: RenditionSelector,
+        format: SubtitleFormat,
+    ) -> DownloadStream {
+        let selector = self.variant.unwrap_or(VariantSelector::Best);
+        self.config
+            .download_hls_subtitles(self.http, self.request, selector, subtitles, format)
+    }
+
+    /// Downloads a MPEG-DASH stream, see [`DownloadConfig::download_dash`].
+    pub fn dash(self) -> DownloadStream {
+        let selector = self.variant.unwrap_or(VariantSelector::Best);
+        self.config.download_dash(self.http, self.request, selector)
+    }
+
+    /// Downloads an audio adaptation set of a MPEG-DASH manifest, see
+    /// [`DownloadConfig::download_dash_audio`].
+    pub fn dash_audio(self, audio: RenditionSelector) -> DownloadStream {
+        self.config
+            .download_dash_audio(self.http, self.request, audio)
+    }
+
+    /// Downloads a single http resource, see
+    /// [`DownloadConfig::download_chunked`].
+    pub fn chunked(self) -> DownloadStream {
+        self.config.download_chunked(self.http, self.request)
+    }
+
+    /// Downloads a live stream served over a single http response, see
+    /// [`DownloadConfig::download_live_chunked`].
+    pub fn live_chunked(self) -> DownloadStream {
+        self.config.download_live_chunked(self.http, self.request)
+    }
+}
@@ -0,0 +1,293 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use reqwest::{
+    header::{
+        HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+    },
+    Client, Request, Response, StatusCode,
+};
+use tracing::{info, warn};
+
+use crate::{
+    download_stream::{Event, EventSender},
+    error::Error,
+    flv::FlvStitcher,
+    hls::clone_request,
+    rate::RateLimiter,
+    refresh::RequestRefresh,
+    retry::{check_status, RetryPolicy},
+    stats::StatsHandle,
+};
+
+/// Downloads a single http resource.
+///
+/// Resources with a known length that accept byte ranges are resumed with a
+/// `Range` request when the connection drops, unless they changed in the
+/// meantime. Other resources end with the connection, unless `live` is set.
+pub(crate) struct ChunkedDownload {
+    pub(crate) client: Client,
+    pub(crate) request: Request,
+    pub(crate) event_tx: EventSender,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) refresh: Option<RequestRefresh>,
+    pub(crate) rate_limit: Option<RateLimiter>,
+    pub(crate) stats: StatsHandle,
+    /// The total duration the response may take, `None` makes it unlimited.
+    pub(crate) timeout: Option<Duration>,
+    /// The timeout between two chunks of a streaming response.
+    pub(crate) download_timeout: Option<Duration>,
+    /// Reconnect whenever the connection ends, as a live stream has no end.
+    /// Http-flv streams are joined into a single flv file.
+    pub(crate) live: bool,
+}
+
+impl ChunkedDownload {
+    pub(crate) async fn run(mut self) -> Result<(), Error> {
+        const TIMEOUT_MAX: Duration = Duration::MAX;
+
+        let mut resume: Option<Resume> = None;
+        let mut flv = self.live.then(FlvStitcher::new);
+        let mut started = false;
+        let mut offset = 0;
+        let mut attempt = 0;
+        loop {
+            let range = resume.as_ref().filter(|_| offset > 0).map(|r| (r, offset));
+            let Some(res) = self.connect(range, &mut attempt).await else {
+                return Ok(());
+            };
+
+            if let Some((resume, offset)) = range {
+                if let Err(error) = resume.check(&res, offset) {
+                    self.send_error(error).await;
+                    return Ok(());
+                }
+            } else if !started {
+                started = true;
+                resume = Resume::new(&res).filter(|_| !self.live);
+                if let Some(total) = res.content_length().filter(|_| !self.live) {
+                    self.send(Event::ContentLength { total }).await;
+                }
+            }
+            if let Some(flv) = &mut flv {
+                flv.start_connection();
+            }
+
+            let mut received = false;
+            let mut stream = res.bytes_stream();
+            let error = loop {
+                let next = stream.next();
+                let item =
+                    match tokio::time::timeout(self.download_timeout.unwrap_or(TIMEOUT_MAX), next)
+                        .await
+                    {
+                        Ok(Some(item)) => item,
+                        Ok(None) => break None,
+                        Err(_) => break Some(Error::Timeout(self.request.url().clone())),
+                    };
+                match item {
+                    Ok(bytes) => {
+                        offset += bytes.len() as u64;
+                        received = true;
+                        self.stats.received(bytes.len());
+                        if let Some(rate_limit) = &self.rate_limit {
+                            rate_limit.acquire(bytes.len()).await;
+                        }
+                        let bytes = match &mut flv {
+                            Some(flv) => match flv.push(bytes) {
+                                Ok(bytes) => bytes,
+                                Err(error) => {
+                                    self.send_error(error).await;
+                                    return Ok(());
+                                }
+                            },
+                            None => bytes,
+                        };
+                        if !bytes.is_empty() {
+                            self.send(Event::Bytes { bytes }).await;
+                        }
+                    }
+                    Err(error) => break Some(error.into()),
+                }
+            };
+
+            let error = match (error, &resume) {
+                (None, Some(resume)) if offset < resume.total => {
+                    Error::ConnectionClosed(self.request.url().clone())
+                }
+                (None, _) if self.live => Error::ConnectionClosed(self.request.url().clone()),
+                (None, _) => return Ok(()),
+                (Some(error), _) => error,
+            };
+            if resume.is_none() && !self.live {
+                self.send_error(error).await;
+                return Ok(());
+            }
+
+            // A connection that got somewhere gets the full number of retries.
+            if received {
+                attempt = 0;
+            }
+            match self.retry.delay(attempt, &error) {
+                Some(delay) if self.live => {
+                    warn!(
+                        "The live stream stopped: {}, reconnecting in {:?}.",
+                        error, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    // The old url may only have been valid for the old connection.
+                    if let Some(refresh) = &self.refresh {
+                        if let Some(request) = refresh.refresh().await {
+                            self.request = request;
+                        }
+                    }
+                }
+                Some(delay) => {
+                    info!(
+                        "Download stopped at {} of {} bytes: {}, resuming in {:?}.",
+                        offset,
+                        resume.as_ref().map_or(0, |r| r.total),
+                        error,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                None => {
+                    self.send_error(error).await;
+                    return Ok(());
+                }
+            }
+            self.stats.retry();
+            attempt += 1;
+        }
+    }
+
+    /// Connects to the resource, from `offset` if resuming. Returns `None`
+    /// after sending the error if it could not be done within the retries.
+    async fn connect(
+        &mut self,
+        range: Option<(&Resume, u64)>,
+        attempt: &mut u32,
+    ) -> Option<Response> {
+        loop {
+            let mut next = clone_request(&self.request, Duration::MAX);
+            *next.timeout_mut() = self.timeout;
+            if let Some((resume, offset)) = range {
+                resume.add_headers(offset, next.headers_mut());
+            }
+            let url = next.url().clone();
+            let res = self
+                .client
+                .execute(next)
+                .await
+                .map_err(Error::from)
+                .and_then(check_status);
+            self.stats.request(&url, res.is_ok());
+            let error = match res {
+                Ok(res) => return Some(res),
+                Err(error) => error,
+            };
+
+            // Expired urls are replaced right away, it counts as an attempt so
+            // a refresh that keeps failing still gives up.
+            let refreshed = match &self.refresh {
+                Some(refresh) if *attempt < self.retry.max_retries => {
+                    refresh.refresh_expired(&error, &mut self.request).await
+                }
+                _ => false,
+            };
+            if !refreshed {
+                match self.retry.delay(*attempt, &error) {
+                    Some(delay) => {
+                        warn!("Connecting failed: {}, retrying in {:?}.", error, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        self.send_error(error).await;
+                        return None;
+                    }
+                }
+            }
+            self.stats.retry();
+            *attempt += 1;
+        }
+    }
+
+    async fn send(&self, event: Event) {
+        if let Err(error) = self.event_tx.send(event).await {
+            warn!("Could not send event: {}", error);
+        };
+    }
+
+    async fn send_error(&self, error: Error) {
+        self.send(Event::Error { error }).await;
+    }
+}
+
+/// What is needed to continue a download where it stopped and to tell if
+/// the resource changed since.
+struct Resume {
+    /// Length of the whole resource.
+    total: u64,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl Resume {
+    /// Returns `None` if the server does not support resuming the response.
+    fn new(res: &Response) -> Option<Self> {
+        if res.status() != StatusCode::OK || res.headers().get(ACCEPT_RANGES)? != "bytes" {
+            return None;
+        }
+        Some(Resume {
+            total: res.content_length()?,
+            etag: res.headers().get(ETAG).cloned(),
+            last_modified: res.headers().get(LAST_MODIFIED).cloned(),
+        })
+    }
+
+    fn add_headers(&self, offset: u64, headers: &mut HeaderMap) {
+        if let Ok(range) = HeaderValue::from_str(&format!("bytes={}-", offset)) {
+            headers.insert(RANGE, range);
+        }
+        // The server sends the whole resource instead of the range if it
+        // changed, weak etags can not be used for this.
+        let validator = self
+            .etag
+            .as_ref()
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+            .or(self.last_modified.as_ref());
+        if let Some(validator) = validator {
+            headers.insert(IF_RANGE, validator.clone());
+        }
+    }
+
+    /// Checks that `res` continues the same resource at `offset`.
+    fn check(&self, res: &Response, offset: u64) -> Result<(), Error> {
+        let headers = res.headers();
+        let same_version = [(ETAG, &self.etag), (LAST_MODIFIED, &self.last_modified)]
+            .into_iter()
+            .all(|(name, value)| value.is_none() || headers.get(name) == value.as_ref());
+        let same_range = headers
+            .get(CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(parse_content_range)
+            .is_some_and(|(start, total)| {
+                start == offset && total.is_none_or(|total| total == self.total)
+            });
+
+        if res.status() == StatusCode::PARTIAL_CONTENT && same_version && same_range {
+            Ok(())
+        } else {
+            Err(Error::ResourceChanged(res.url().clone()))
+        }
+    }
+}
+
+/// Parses the start and total length of `bytes <start>-<end>/<total>`, the
+/// total may be `*`.
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
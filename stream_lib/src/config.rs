@@ -0,0 +1,278 @@
+use std::time::Duration;
+
+use reqwest::{Client, Request};
+
+use crate::{
+    chunked::ChunkedDownload,
+    dash::DashWatch,
+    download_stream::{BufferSize, DownloadStream, Overflow},
+    hls::{probe_extension, HlsDownloader, SegmentFilter, SubtitleFormat, Track, HLS_MAX_RETRIES},
+    rate::RateLimiter,
+    refresh::RequestRefresh,
+    retry::RetryPolicy,
+    variant::{RenditionSelector, VariantSelector},
+    Error,
+};
+
+/// Settings for a download.
+///
+/// The `download_*` methods start a download using these settings, see
+/// [`StreamBuilder`](crate::StreamBuilder) to set them one by one.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// How much downloaded data may be waiting for the consumer of the
+    /// [`DownloadStream`] before the download slows down.
+    pub buffer: BufferSize,
+    /// What to do when the buffer is full.
+    pub overflow: Overflow,
+    /// How many HLS segments are downloaded at the same time, they are
+    /// still emitted in playlist order. Defaults to 1.
+    pub concurrency: usize,
+    /// How failed segment downloads are retried.
+    pub retry: RetryPolicy,
+    /// Follow Low-Latency HLS playlists part by part using blocking
+    /// playlist reloads, instead of waiting for whole segments.
+    pub low_latency: bool,
+    /// Supplies a new request when the server rejects the playlist, manifest
+    /// or chunked stream url with 401, 403 or 410, e.g. because a signed
+    /// url expired. Without it the download ends once the retries run out.
+    pub refresh: Option<RequestRefresh>,
+    /// Caps how fast segments and chunked streams are received, the same
+    /// limiter can be shared by several downloads.
+    pub rate_limit: Option<RateLimiter>,
+    /// How long a playlist or manifest request may take. Defaults to 10
+    /// seconds.
+    pub playlist_timeout: Duration,
+    /// How long a segment may take to start and to send each chunk of its
+    /// body. Defaults to 10 seconds.
+    pub segment_timeout: Duration,
+    /// How long [`DownloadConfig::download_chunked`] may receive nothing
+    /// before the connection is given up on. Defaults to 60 seconds.
+    pub chunked_timeout: Duration,
+    /// How long [`DownloadConfig::download_live_chunked`] may receive nothing
+    /// before it reconnects, a live stream sends data all the time. Defaults
+    /// to 10 seconds.
+    pub live_chunked_timeout: Duration,
+    /// How many reloads of a playlist or manifest in a row may fail before
+    /// the download fails with the last error. Defaults to 12.
+    pub playlist_retries: usize,
+    /// How long a live playlist or manifest that still loads may go without
+    /// new segments before the stream is considered over. Defaults to 60
+    /// seconds.
+    ///
+    /// It is only checked after a reload that brought nothing new, and never
+    /// ends a stream sooner than three target durations (or DASH minimum
+    /// update periods) after its last new segment.
+    pub end_timeout: Duration,
+    /// How much longer than the target duration HLS segments may be, many
+    /// sites publish segments that are longer than they claim. Defaults to
+    /// 10 seconds.
+    pub allowable_excess_duration: Duration,
+    /// How often live playlists and manifests are reloaded. By default HLS
+    /// playlists are reloaded after the target duration if they changed and
+    /// after half of it if not, as RFC 8216 says, and DASH manifests after
+    /// the minimum update period or the shortest new segment. Low-Latency
+    /// HLS playlists are not affected.
+    pub reload_interval: Option<Duration>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            buffer: BufferSize::default(),
+            overflow: Overflow::default(),
+            concurrency: 1,
+            retry: RetryPolicy::default(),
+            low_latency: false,
+            refresh: None,
+            rate_limit: None,
+            playlist_timeout: Duration::from_secs(10),
+            segment_timeout: Duration::from_secs(10),
+            chunked_timeout: Duration::from_secs(60),
+            live_chunked_timeout: Duration::from_secs(10),
+            playlist_retries: HLS_MAX_RETRIES,
+            end_timeout: Duration::from_secs(60),
+            allowable_excess_duration: Duration::from_secs(10),
+            reload_interval: None,
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// Guesses the file extension of a HLS stream, `"mp4"` for fragmented
+    /// MP4 streams using `EXT-X-MAP` and `"ts"` otherwise. The playlist is
+    /// parsed with [`DownloadConfig::allowable_excess_duration`].
+    ///
+    /// Of a master playlist the variant picked by `selector` is probed, or
+    /// the first one without a selector.
+    pub async fn hls_extension(
+        &self,
+        http: Client,
+        request: Request,
+        selector: Option<&VariantSelector>,
+    ) -> Result<&'static str, Error> {
+        probe_extension(http, request, selector, self.allowable_excess_duration).await
+    }
+
+    pub fn download_hls(
+        &self,
+        http: Client,
+        request: Request,
+        filter: Option<SegmentFilter>,
+    ) -> DownloadStream {
+        HlsDownloader::new(request, http, filter).download(self)
+    }
+
+    pub fn download_hls_named(
+        &self,
+        http: Client,
+        request: Request,
+        name: String,
+        filter: Option<SegmentFilter>,
+    ) -> DownloadStream {
+        self.download_hls_variant(http, request, VariantSelector::Name(name), filter)
+    }
+
+    pub fn download_hls_master_first(
+        &self,
+        http: Client,
+        request: Request,
+        filter: Option<SegmentFilter>,
+    ) -> DownloadStream {
+        self.download_hls_variant(http, request, VariantSelector::First, filter)
+    }
+
+    /// Downloads the variant of a master playlist picked by `selector`.
+    pub fn download_hls_variant(
+        &self,
+        http: Client,
+        request: Request,
+        selector: VariantSelector,
+        filter: Option<SegmentFilter>,
+    ) -> DownloadStream {
+        HlsDownloader::new_variant(request, http, selector, Track::Variant, filter).download(self)
+    }
+
+    /// Downloads an alternative audio rendition of the variant picked by
+    /// `selector`, for streams where the audio is not part of the variant.
+    ///
+    /// Use it together with [`DownloadConfig::download_hls_variant`] and the
+    /// same selector to get both the video and the audio, the stream ends
+    /// right away if the variant has no separate audio.
+    pub fn download_hls_audio(
+        &self,
+        http: Client,
+        request: Request,
+        selector: VariantSelector,
+        audio: RenditionSelector,
+        filter: Option<SegmentFilter>,
+    ) -> DownloadStream {
+        HlsDownloader::new_variant(request, http, selector, Track::Audio(audio), filter)
+            .download(self)
+    }
+
+    /// Downloads a subtitle rendition of the variant picked by `selector` and
+    /// stitches its WebVTT segments into a single file in `format`.
+    ///
+    /// Cue times count from the start of a recording of the variant started
+    /// at the same time. The two playlists are lined up by their program date
+    /// times, or by their media sequence numbers if they have none.
+    ///
+    /// The stream ends right away if the variant has no such subtitles.
+    pub fn download_hls_subtitles(
+        &self,
+        http: Client,
+        request: Request,
+        selector: VariantSelector,
+        subtitles: RenditionSelector,
+        format: SubtitleFormat,
+    ) -> DownloadStream {
+        HlsDownloader::new_variant(request, http, selector, Track::Subtitles(subtitles), None)
+            .stitch_subtitles(format)
+            .download(self)
+    }
+
+    /// Downloads the representation picked by `selector` from a MPEG-DASH
+    /// manifest, the init segment is sent before the first media segment.
+    ///
+    /// Static presentations end after the last segment, live presentations
+    /// start a few segments from the live edge and are followed until the
+    /// manifest stops changing. The selector picks among the video
+    /// representations, or the audio ones if there is no video.
+    pub fn download_dash(
+        &self,
+        http: Client,
+        request: Request,
+        selector: VariantSelector,
+    ) -> DownloadStream {
+        let (watch, rx) = DashWatch::new(request, http, selector, Track::Variant);
+        watch.download(self, rx)
+    }
+
+    /// Downloads the best representation of the audio adaptation set picked
+    /// by `audio`, adaptation sets are named by their label or id.
+    pub fn download_dash_audio(
+        &self,
+        http: Client,
+        request: Request,
+        audio: RenditionSelector,
+    ) -> DownloadStream {
+        let (watch, rx) = DashWatch::new(request, http, VariantSelector::Best, Track::Audio(audio));
+        watch.download(self, rx)
+    }
+
+    /// Downloads a single http resource, like a progressive mp4, see
+    /// [`DownloadConfig::download_live_chunked`] for live streams.
+    ///
+    /// [`Event::ContentLength`](crate::Event::ContentLength) is sent first
+    /// if the server tells the length. Downloads of resources that accept
+    /// byte ranges continue where they stopped when the connection drops,
+    /// and fail with [`Error::ResourceChanged`](crate::Error::ResourceChanged)
+    /// if the resource changed in between.
+    pub fn download_chunked(&self, http: Client, request: Request) -> DownloadStream {
+        let (mut dl, tx) = DownloadStream::new(self.buffer, self.overflow);
+        dl.spawn(
+            ChunkedDownload {
+                client: http,
+                request,
+                event_tx: tx,
+                retry: self.retry,
+                refresh: self.refresh.clone(),
+                rate_limit: self.rate_limit.clone(),
+                stats: dl.stats(),
+                timeout: None,
+                download_timeout: Some(self.chunked_timeout),
+                live: false,
+            }
+            .run(),
+        );
+        dl
+    }
+
+    /// Downloads a live stream served over a single http response, like
+    /// http-flv, and reconnects whenever the connection ends, until it
+    /// failed `retry.max_retries` times in a row.
+    ///
+    /// The [`refresh`](DownloadConfig::refresh) callback is asked for a new
+    /// url before every reconnect. Flv streams are joined into one playable
+    /// file, other streams are written one connection after the other.
+    pub fn download_live_chunked(&self, http: Client, request: Request) -> DownloadStream {
+        let (mut dl, tx) = DownloadStream::new(self.buffer, self.overflow);
+        dl.spawn(
+            ChunkedDownload {
+                client: http,
+                request,
+                event_tx: tx,
+                retry: self.retry,
+                refresh: self.refresh.clone(),
+                rate_limit: self.rate_limit.clone(),
+                stats: dl.stats(),
+                timeout: None,
+                download_timeout: Some(self.live_chunked_timeout),
+                live: true,
+            }
+            .run(),
+        );
+        dl
+    }
+}
@@ -0,0 +1,454 @@
+mod mpd;
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::{header::RANGE, Client, Request, Url};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    config::DownloadConfig,
+    download_stream::DownloadStream,
+    hls::{
+        clone_request, forward, report_error, HlsQueue, ReloadConfig, ReloadTimer, Segment,
+        SegmentInfo, Track, HLS_QUEUE_SIZE,
+    },
+    refresh::RequestRefresh,
+    retry::check_status,
+    stats::StatsHandle,
+    Error, RenditionInfo, VariantSelector,
+};
+
+use mpd::{AdaptationSet, Addressing, ContentType, Manifest, Period, Representation, SegmentIndex};
+
+/// How many segments from the live edge a live recording starts.
+const LIVE_EDGE_SEGMENTS: usize = 3;
+
+/// How often a dynamic manifest without segments or update period is
+/// reloaded.
+const DEFAULT_UPDATE: Duration = Duration::from_secs(2);
+
+/// Segment times closer than this are the same.
+const TOLERANCE: Duration = Duration::from_millis(1);
+
+/// Follows a MPEG-DASH manifest and queues the segments of the selected
+/// representation.
+pub(crate) struct DashWatch {
+    tx: Sender<HlsQueue>,
+    request: Request,
+    http: Client,
+    selector: VariantSelector,
+    track: Track,
+    reload: ReloadConfig,
+    /// End of the last queued segment on the presentation timeline.
+    last_end: Option<Duration>,
+    /// The period of the last queued segment.
+    last_period: Option<String>,
+    /// The segment indexes of representations that are a single resource.
+    indexes: HashMap<Url, SegmentIndex>,
+    /// Replaces the request when the manifest url expires.
+    refresh: Option<RequestRefresh>,
+    stats: StatsHandle,
+}
+
+impl DashWatch {
+    pub(crate) fn new(
+        request: Request,
+        http: Client,
+        selector: VariantSelector,
+        track: Track,
+    ) -> (Self, Receiver<HlsQueue>) {
+        let (tx, rx) = channel(HLS_QUEUE_SIZE);
+        (
+            DashWatch {
+                tx,
+                request,
+                http,
+                selector,
+                track,
+                reload: ReloadConfig::default(),
+                last_end: None,
+                last_period: None,
+                indexes: HashMap::new(),
+                refresh: None,
+                stats: StatsHandle::new(),
+            },
+            rx,
+        )
+    }
+
+    pub(crate) fn download(
+        mut self,
+        config: &DownloadConfig,
+        rx: Receiver<HlsQueue>,
+    ) -> DownloadStream {
+        self.refresh = config.refresh.clone();
+        self.reload = ReloadConfig::from(config);
+        let http = self.http.clone();
+        let headers = self.request.headers().clone();
+        let mut download_stream = forward(http, headers, rx, config, None);
+        self.stats = download_stream.stats();
+        let tx = self.tx.clone();
+        download_stream.spawn(report_error(tx, self.run()));
+        download_stream
+    }
+
+    pub(crate) async fn run(mut self) -> Result<(), Error> {
+        let mut warned = false;
+        let mut timer = ReloadTimer::new(self.reload);
+
+        loop {
+            if timer.over()? {
+                return self.send(HlsQueue::StreamOver).await;
+            }
+
+            let req = clone_request(&self.request, self.reload.timeout);
+            let url = req.url().clone();
+            timer.start();
+            let reload = Instant::now();
+            let res = self
+                .http
+                .execute(req)
+                .await
+                .map_err(Error::from)
+                .and_then(check_status);
+            self.stats.request(&url, res.is_ok());
+            let res = match res {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("[DASH] Manifest download failed!\n{}", e);
+                    if let Some(refresh) = &self.refresh {
+                        refresh.refresh_expired(&e, &mut self.request).await;
+                    }
+                    timer.failed(Some(e)).await;
+                    continue;
+                }
+            };
+
+            // Segments are relative to the manifest after redirects.
+            let url = res.url().clone();
+            let text = match res.text().await {
+                Ok(t) => t,
+                Err(e) => {
+                    warn!("[DASH] Manifest text failed!\n{}", e);
+                    timer.failed(Some(e.into())).await;
+                    continue;
+                }
+            };
+            self.stats.playlist_reloaded(reload.elapsed());
+
+            let manifest = match Manifest::parse(&text, &url) {
+                Ok(m) => m,
+                Err(e) => {
+                    let e = Error::playlist(&url, &text, e);
+                    warn!("[DASH] {}", e);
+                    trace!("[DASH]\n{}", &text);
+                    timer.failed(Some(e)).await;
+                    continue;
+                }
+            };
+            timer.loaded(manifest.minimum_update_period.unwrap_or(DEFAULT_UPDATE));
+
+            if manifest.protected && !warned {
+                warn!("[DASH] The stream is encrypted, the recording will not be playable.");
+                warned = true;
+            }
+
+            if let Err(e) = self.load_indexes(&manifest).await {
+                warn!("[DASH] Segment index download failed!\n{}", e);
+                timer.failed(Some(e)).await;
+                continue;
+            }
+
+            let segments = match self.new_segments(&manifest) {
+                Ok(Some(segments)) => segments,
+                Ok(None) => {
+                    // The selected track is not in the presentation.
+                    return self.send(HlsQueue::StreamOver).await;
+                }
+                Err(e) => {
+                    let e = Error::playlist(&url, &text, e);
+                    warn!("[DASH] {}", e);
+                    timer.failed(Some(e)).await;
+                    continue;
+                }
+            };
+
+            let mut wait = manifest.minimum_update_period;
+            for (start, segment) in segments {
+                timer.progress();
+                let duration = segment.info.duration;
+                wait = Some(wait.map_or(duration, |w| w.min(duration)));
+
+                if let Some(last_end) = self.last_end {
+                    if start > last_end + TOLERANCE {
+                        let missing = start - last_end;
+                        let segments = (missing.as_secs_f64() / duration.as_secs_f64().max(0.001))
+                            .round() as usize;
+                        warn!(
+                            "[DASH] {} segments left the time shift buffer before they were seen.",
+                            segments
+                        );
+                        self.send(HlsQueue::Gap {
+                            segments: segments.max(1),
+                            duration: missing,
+                        })
+                        .await?;
+                    }
+                }
+                self.last_end = Some(start + duration);
+
+                debug!("[DASH] Adds {}!", segment.url);
+                self.stats.queued(duration);
+                self.send(HlsQueue::Segment(Box::new(segment))).await?;
+            }
+
+            if !manifest.dynamic {
+                tracing::debug!("The presentation is static, no more segments expected.");
+                return self.send(HlsQueue::StreamOver).await;
+            }
+
+            let wait = wait
+                .unwrap_or(DEFAULT_UPDATE)
+                .max(Duration::from_millis(500));
+            if timer.stalled() {
+                return self.send(HlsQueue::StreamOver).await;
+            }
+            timer.sleep(wait).await;
+        }
+    }
+
+    /// Lists the segments that have not been queued yet together with where
+    /// they start on the presentation timeline, `None` if the track is
+    /// missing.
+    fn new_segments(
+        &mut self,
+        manifest: &Manifest,
+    ) -> Result<Option<Vec<(Duration, Segment)>>, Error> {
+        // Where the live edge is on the presentation timeline.
+        let now = match (manifest.dynamic, manifest.availability_start) {
+            (true, Some(start)) => {
+                let now = DateTime::<Utc>::from(SystemTime::now());
+                Some((now.fixed_offset() - start).to_std().unwrap_or_default())
+            }
+            (true, None) => {
+                return Err(Error::Mpd(
+                    "dynamic manifest without availabilityStartTime".to_string(),
+                ))
+            }
+            (false, _) => None,
+        };
+
+        let mut found = false;
+        let mut segments = Vec::new();
+        for period in &manifest.periods {
+            if now.is_some_and(|now| period.start > now) {
+                break;
+            }
+            let Some(representation) = self.select(period) else {
+                continue;
+            };
+            found = true;
+
+            let map = representation.init()?;
+            let period_now = now.map(|now| now - period.start);
+            let last_end = self.last_end.map(|end| end.saturating_sub(period.start));
+            for segment in representation.segments(
+                period,
+                period_now,
+                manifest.time_shift_buffer_depth,
+                last_end,
+                self.indexes.get(&representation.base_url),
+            )? {
+                let end = period.start + segment.start + segment.duration;
+                if self.last_end.is_some_and(|last| end <= last + TOLERANCE) {
+                    continue;
+                }
+
+                let program_date_time = manifest
+                    .availability_start
+                    .and_then(|ast| {
+                        let offset =
+                            chrono::Duration::from_std(period.start + segment.start).ok()?;
+                        ast.checked_add_signed(offset)
+                    })
+                    .filter(|_| manifest.dynamic);
+                let info = SegmentInfo {
+                    uri: segment.uri,
+                    sequence: segment.number as usize,
+                    part: None,
+                    duration: segment.duration,
+                    title: None,
+                    discontinuity: self
+                        .last_period
+                        .as_ref()
+                        .is_some_and(|last| last != &period.id),
+                    program_date_time,
+                };
+                self.last_period = Some(period.id.clone());
+                segments.push((
+                    period.start + segment.start,
+                    Segment {
+                        url: segment.url,
+                        info,
+                        key: None,
+                        map: map.clone(),
+                        range: segment.range,
+                    },
+                ));
+            }
+        }
+
+        // Live recordings start close to the live edge, like a player would.
+        if self.last_end.is_none() && manifest.dynamic && segments.len() > LIVE_EDGE_SEGMENTS {
+            segments.drain(..segments.len() - LIVE_EDGE_SEGMENTS);
+            if let Some((_, first)) = segments.first_mut() {
+                first.info.discontinuity = false;
+            }
+        }
+
+        Ok(found.then_some(segments))
+    }
+
+    /// Downloads the segment index of the selected representations that are
+    /// a single resource, or their size if they have none.
+    async fn load_indexes(&mut self, manifest: &Manifest) -> Result<(), Error> {
+        for period in &manifest.periods {
+            let Some(representation) = self.select(period) else {
+                continue;
+            };
+            let Addressing::Base(base) = &representation.addressing else {
+                continue;
+            };
+            let url = &representation.base_url;
+            if self.indexes.contains_key(url) {
+                continue;
+            }
+
+            let mut req = self
+                .http
+                .get(url.clone())
+                .headers(self.request.headers().clone());
+            if let Some(range) = &base.index_range {
+                if range.is_empty() {
+                    return Err(Error::Mpd(format!("the index range of {} is empty", url)));
+                }
+                // The end of a http range is inclusive.
+                req = req.header(RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+            } else {
+                req = req.header(RANGE, "bytes=0-0");
+            }
+            let res = req
+                .timeout(self.reload.timeout)
+                .send()
+                .await
+                .map_err(Error::from)
+                .and_then(check_status);
+            self.stats.request(url, res.is_ok());
+            let res = res?;
+
+            let index = match &base.index_range {
+                Some(range) => {
+                    let partial = res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+                    let mut data = res.bytes().await?;
+                    if !partial {
+                        // The server ignored the range and sent everything.
+                        data = data.slice(range.start.min(data.len())..range.end.min(data.len()));
+                    }
+                    SegmentIndex::parse(&data, range)?
+                }
+                None => {
+                    let length = content_range_length(&res)
+                        .ok_or_else(|| Error::Mpd(format!("the size of {} is unknown", url)))?;
+                    // The init section is not part of the media segments.
+                    let start = base.initialization.as_ref().map_or(0, |init| init.end);
+                    SegmentIndex::split(start, length, period.duration)
+                }
+            };
+            self.indexes.insert(url.clone(), index);
+        }
+        Ok(())
+    }
+
+    /// Picks the representation of the track in `period`.
+    fn select<'a>(&self, period: &'a Period) -> Option<&'a Representation> {
+        let of_type = |content_type| {
+            period
+                .adaptation_sets
+                .iter()
+                .filter(move |s| s.content_type == content_type)
+        };
+        let best = |set: &'a AdaptationSet| set.representations.iter().max_by_key(|r| r.bandwidth);
+
+        let content_type = match &self.track {
+            Track::Variant => {
+                // Audio only presentations are selected like video.
+                let representations: Vec<&Representation> =
+                    [ContentType::Video, ContentType::Other, ContentType::Audio]
+                        .into_iter()
+                        .map(|t| {
+                            of_type(t)
+                                .flat_map(|s| &s.representations)
+                                .collect::<Vec<_>>()
+                        })
+                        .find(|r| !r.is_empty())?;
+                let infos: Vec<_> = representations.iter().map(|r| r.info()).collect();
+                return match self.selector.select(&infos) {
+                    Some(i) => Some(representations[i]),
+                    None => {
+                        warn!("[DASH] No representation matches {:?}", self.selector);
+                        None
+                    }
+                };
+            }
+            Track::Audio(_) => ContentType::Audio,
+            Track::Subtitles(_) => ContentType::Text,
+        };
+
+        let sets: Vec<_> = of_type(content_type).collect();
+        let infos: Vec<_> = sets
+            .iter()
+            .map(|s| RenditionInfo {
+                name: s
+                    .label
+                    .clone()
+                    .or_else(|| s.id.clone())
+                    .or_else(|| s.lang.clone())
+                    .unwrap_or_default(),
+                language: s.lang.clone(),
+                default: s.main,
+            })
+            .collect();
+        let (selector, _) = self.track.rendition()?;
+        match selector.select(&infos) {
+            Some(i) => best(sets[i]),
+            None => {
+                warn!(
+                    "[DASH] No {:?} adaptation set matches {:?}",
+                    content_type, selector
+                );
+                None
+            }
+        }
+    }
+
+    async fn send(&self, item: HlsQueue) -> Result<(), Error> {
+        if self.tx.send(item).await.is_err() {
+            return Err(Error::Stopped);
+        }
+        Ok(())
+    }
+}
+
+/// The total size of the resource from the `Content-Range` of a partial
+/// response, or the `Content-Length` if the server sent all of it.
+fn content_range_length(res: &reqwest::Response) -> Option<usize> {
+    match res.headers().get(reqwest::header::CONTENT_RANGE) {
+        Some(range) => range.to_str().ok()?.rsplit_once('/')?.1.parse().ok(),
+        None => res.content_length().map(|l| l as usize),
+    }
+}
@@ -0,0 +1,1109 @@
+use std::{ops::Range, time::Duration};
+
+use chrono::{DateTime, FixedOffset};
+use reqwest::Url;
+use roxmltree::{Document, Node};
+
+use crate::{
+    hls::{segment_url, InitSection},
+    Error, VariantInfo,
+};
+
+use super::LIVE_EDGE_SEGMENTS;
+
+/// Size of the segments a single resource without a segment index is split
+/// into.
+const SPLIT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Most segments a `SegmentTimeline` is expanded into.
+const MAX_TIMELINE_SEGMENTS: u64 = 1 << 20;
+
+/// A parsed Media Presentation Description.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    /// Live presentations are `dynamic` and have to be reloaded.
+    pub(crate) dynamic: bool,
+    pub(crate) availability_start: Option<DateTime<FixedOffset>>,
+    pub(crate) minimum_update_period: Option<Duration>,
+    pub(crate) time_shift_buffer_depth: Option<Duration>,
+    /// Set if any of the content is encrypted, which is not supported.
+    pub(crate) protected: bool,
+    pub(crate) periods: Vec<Period>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Period {
+    pub(crate) id: String,
+    /// Start of the period on the presentation timeline.
+    pub(crate) start: Duration,
+    pub(crate) duration: Option<Duration>,
+    pub(crate) adaptation_sets: Vec<AdaptationSet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentType {
+    Video,
+    Audio,
+    Text,
+    Other,
+}
+
+#[derive(Debug)]
+pub(crate) struct AdaptationSet {
+    pub(crate) id: Option<String>,
+    pub(crate) content_type: ContentType,
+    pub(crate) lang: Option<String>,
+    pub(crate) label: Option<String>,
+    /// Has the `main` role, the set to use when there is a choice.
+    pub(crate) main: bool,
+    pub(crate) representations: Vec<Representation>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Representation {
+    pub(crate) id: String,
+    pub(crate) bandwidth: u64,
+    pub(crate) width: Option<usize>,
+    pub(crate) height: Option<usize>,
+    pub(crate) frame_rate: Option<f32>,
+    pub(crate) codecs: Vec<String>,
+    pub(crate) base_url: Url,
+    pub(crate) addressing: Addressing,
+}
+
+/// How the segments of a representation are found.
+#[derive(Debug)]
+pub(crate) enum Addressing {
+    Template(SegmentTemplate),
+    List(SegmentList),
+    /// The representation is a single resource, it is split into segments
+    /// with its [`SegmentIndex`].
+    Base(SegmentBase),
+}
+
+#[derive(Debug)]
+pub(crate) struct SegmentTemplate {
+    media: Option<String>,
+    initialization: Option<String>,
+    start_number: u64,
+    timescale: u64,
+    /// Duration of every segment, unless there is a timeline.
+    duration: Option<u64>,
+    presentation_time_offset: u64,
+    timeline: Vec<TimelineEntry>,
+}
+
+/// An `S` element of a `SegmentTimeline`.
+#[derive(Debug)]
+struct TimelineEntry {
+    start: Option<u64>,
+    duration: u64,
+    /// Number of repeats after the first segment, `-1` repeats until the
+    /// next entry or the end of the period.
+    repeat: i64,
+}
+
+#[derive(Debug)]
+pub(crate) struct SegmentList {
+    start_number: u64,
+    timescale: u64,
+    duration: Option<u64>,
+    initialization: Option<(Option<String>, Option<Range<usize>>)>,
+    segments: Vec<(Option<String>, Option<Range<usize>>)>,
+}
+
+/// A `SegmentBase`, or just a `BaseURL`.
+#[derive(Debug, Default)]
+pub(crate) struct SegmentBase {
+    timescale: u64,
+    presentation_time_offset: u64,
+    /// Where the init section is.
+    pub(crate) initialization: Option<Range<usize>>,
+    /// Where the `sidx` box is.
+    pub(crate) index_range: Option<Range<usize>>,
+}
+
+/// The byte ranges and durations of the segments of a single resource.
+#[derive(Debug, Clone)]
+pub(crate) struct SegmentIndex {
+    /// Start of the first segment in timescale units.
+    start: u64,
+    timescale: u64,
+    segments: Vec<(Range<usize>, u64)>,
+}
+
+/// A media segment of a representation.
+#[derive(Debug)]
+pub(crate) struct DashSegment {
+    pub(crate) number: u64,
+    /// The uri after filling in the template.
+    pub(crate) uri: String,
+    pub(crate) url: Url,
+    pub(crate) range: Option<Range<usize>>,
+    /// Start of the segment relative to the start of the period.
+    pub(crate) start: Duration,
+    pub(crate) duration: Duration,
+}
+
+impl Manifest {
+    /// Parses the manifest found at `url`, relative urls are resolved against
+    /// it.
+    pub(crate) fn parse(text: &str, url: &Url) -> Result<Self, Error> {
+        let doc = Document::parse(text)?;
+        let mpd = doc.root_element();
+        if mpd.tag_name().name() != "MPD" {
+            return Err(Error::Mpd(format!(
+                "expected a MPD element, got {}",
+                mpd.tag_name().name()
+            )));
+        }
+
+        let dynamic = mpd.attribute("type") == Some("dynamic");
+        let presentation_duration = mpd
+            .attribute("mediaPresentationDuration")
+            .and_then(parse_duration);
+        let base = base_url(mpd, url);
+
+        let mut periods = Vec::new();
+        let mut next_start = Duration::ZERO;
+        for (i, node) in children(mpd, "Period").enumerate() {
+            let start = node
+                .attribute("start")
+                .and_then(parse_duration)
+                .unwrap_or(next_start);
+            let period = Period::parse(node, i, start, &base)?;
+            next_start = start + period.duration.unwrap_or_default();
+            periods.push(period);
+        }
+
+        // Periods without a duration last until the next one starts, or
+        // until the end of the presentation.
+        for i in 0..periods.len() {
+            if periods[i].duration.is_none() {
+                let end = periods
+                    .get(i + 1)
+                    .map(|p| p.start)
+                    .or(presentation_duration);
+                periods[i].duration = end.and_then(|end| end.checked_sub(periods[i].start));
+            }
+        }
+
+        Ok(Manifest {
+            dynamic,
+            availability_start: mpd.attribute("availabilityStartTime").and_then(parse_date),
+            minimum_update_period: mpd
+                .attribute("minimumUpdatePeriod")
+                .and_then(parse_duration),
+            time_shift_buffer_depth: mpd
+                .attribute("timeShiftBufferDepth")
+                .and_then(parse_duration),
+            protected: mpd
+                .descendants()
+                .any(|n| n.tag_name().name() == "ContentProtection"),
+            periods,
+        })
+    }
+}
+
+impl Period {
+    fn parse(node: Node<'_, '_>, index: usize, start: Duration, base: &Url) -> Result<Self, Error> {
+        let base = base_url(node, base);
+        let adaptation_sets = children(node, "AdaptationSet")
+            .map(|set| AdaptationSet::parse(node, set, &base))
+            .collect::<Result<_, _>>()?;
+        Ok(Period {
+            id: node
+                .attribute("id")
+                .map_or_else(|| index.to_string(), str::to_string),
+            start,
+            duration: node.attribute("duration").and_then(parse_duration),
+            adaptation_sets,
+        })
+    }
+}
+
+impl AdaptationSet {
+    fn parse(period: Node<'_, '_>, node: Node<'_, '_>, base: &Url) -> Result<Self, Error> {
+        let base = base_url(node, base);
+        let representations: Vec<_> = children(node, "Representation")
+            .map(|rep| Representation::parse(period, node, rep, &base))
+            .collect::<Result<_, _>>()?;
+
+        let content_type = match node.attribute("contentType").or_else(|| {
+            node.attribute("mimeType")
+                .or_else(|| children(node, "Representation").find_map(|r| r.attribute("mimeType")))
+                .and_then(|m| m.split('/').next())
+        }) {
+            Some("video") => ContentType::Video,
+            Some("audio") => ContentType::Audio,
+            Some("text") => ContentType::Text,
+            // Subtitles in fragmented MP4 are `application/mp4`.
+            _ if representations
+                .iter()
+                .flat_map(|r| &r.codecs)
+                .any(|c| c.starts_with("stpp") || c.starts_with("wvtt")) =>
+            {
+                ContentType::Text
+            }
+            _ => ContentType::Other,
+        };
+
+        Ok(AdaptationSet {
+            id: node.attribute("id").map(str::to_string),
+            content_type,
+            lang: node.attribute("lang").map(str::to_string),
+            label: children(node, "Label")
+                .next()
+                .and_then(|l| l.text())
+                .or_else(|| node.attribute("label"))
+                .map(|l| l.trim().to_string()),
+            main: children(node, "Role").any(|r| r.attribute("value") == Some("main")),
+            representations,
+        })
+    }
+}
+
+impl Representation {
+    fn parse(
+        period: Node<'_, '_>,
+        set: Node<'_, '_>,
+        node: Node<'_, '_>,
+        base: &Url,
+    ) -> Result<Self, Error> {
+        let attr = |name| node.attribute(name).or_else(|| set.attribute(name));
+        let id = node
+            .attribute("id")
+            .ok_or_else(|| Error::Mpd("Representation without an id".to_string()))?;
+
+        // Segment information is inherited from the adaptation set and the
+        // period, the most specific element wins.
+        let levels = [node, set, period];
+        let find = |name| -> Vec<Node<'_, '_>> {
+            levels
+                .iter()
+                .filter_map(|n| children(*n, name).next())
+                .collect()
+        };
+        let templates = find("SegmentTemplate");
+        let lists = find("SegmentList");
+        let addressing = if !templates.is_empty() {
+            Addressing::Template(SegmentTemplate::parse(&templates))
+        } else if !lists.is_empty() {
+            Addressing::List(SegmentList::parse(&lists))
+        } else {
+            Addressing::Base(SegmentBase::parse(&find("SegmentBase")))
+        };
+
+        Ok(Representation {
+            id: id.to_string(),
+            bandwidth: node
+                .attribute("bandwidth")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0),
+            width: attr("width").and_then(|w| w.parse().ok()),
+            height: attr("height").and_then(|h| h.parse().ok()),
+            frame_rate: attr("frameRate").and_then(parse_frame_rate),
+            codecs: attr("codecs")
+                .map(|c| c.split(',').map(|c| c.trim().to_string()).collect())
+                .unwrap_or_default(),
+            base_url: base_url(node, base),
+            addressing,
+        })
+    }
+
+    pub(crate) fn info(&self) -> VariantInfo {
+        VariantInfo {
+            bandwidth: self.bandwidth,
+            resolution: self.width.zip(self.height),
+            frame_rate: self.frame_rate,
+            codecs: self.codecs.clone(),
+            names: vec![self.id.clone()],
+        }
+    }
+
+    /// The initialization segment the media segments depend on.
+    pub(crate) fn init(&self) -> Result<Option<InitSection>, Error> {
+        let (uri, range) = match &self.addressing {
+            Addressing::Template(template) => match &template.initialization {
+                Some(init) => (Some(self.expand(init, template.start_number, 0)), None),
+                None => return Ok(None),
+            },
+            Addressing::List(list) => match &list.initialization {
+                Some((uri, range)) => (uri.clone(), range.clone()),
+                None => return Ok(None),
+            },
+            // Without an initialization range everything before the index
+            // is the init section.
+            Addressing::Base(base) => match (&base.initialization, &base.index_range) {
+                (Some(range), _) => (None, Some(range.clone())),
+                (None, Some(index)) if index.start > 0 => (None, Some(0..index.start)),
+                _ => return Ok(None),
+            },
+        };
+        let url = match uri {
+            Some(uri) => segment_url(&self.base_url, &uri)?,
+            None => self.base_url.clone(),
+        };
+        Ok(Some(InitSection {
+            url,
+            range,
+            key: None,
+        }))
+    }
+
+    /// Lists the segments of the representation in `period`.
+    ///
+    /// For live presentations `now` is the current time relative to the
+    /// start of the period, segments that are not yet complete or have left
+    /// the time shift buffer are left out. `last_end` is the end of the last
+    /// queued segment relative to the start of the period, without it a live
+    /// template starts close to the live edge. Representations that are a
+    /// single resource need their `index`.
+    pub(crate) fn segments(
+        &self,
+        period: &Period,
+        now: Option<Duration>,
+        depth: Option<Duration>,
+        last_end: Option<Duration>,
+        index: Option<&SegmentIndex>,
+    ) -> Result<Vec<DashSegment>, Error> {
+        match &self.addressing {
+            Addressing::Template(template) => {
+                self.template_segments(template, period, now, depth, last_end)
+            }
+            Addressing::List(list) => {
+                let mut start = 0;
+                let mut segments = Vec::new();
+                for (i, (uri, range)) in list.segments.iter().enumerate() {
+                    let duration = list.duration.unwrap_or(0);
+                    segments.push(DashSegment {
+                        number: list.start_number + i as u64,
+                        uri: uri.clone().unwrap_or_default(),
+                        url: match uri {
+                            Some(uri) => segment_url(&self.base_url, uri)?,
+                            None => self.base_url.clone(),
+                        },
+                        range: range.clone(),
+                        start: scaled(start, list.timescale),
+                        duration: scaled(duration, list.timescale),
+                    });
+                    start += duration;
+                }
+                Ok(segments)
+            }
+            Addressing::Base(base) => {
+                let index = index.ok_or_else(|| {
+                    Error::Mpd(format!("the segment index of {} is not loaded", self.id))
+                })?;
+                let mut time = index.start;
+                let mut segments = Vec::new();
+                for (i, (range, duration)) in index.segments.iter().enumerate() {
+                    segments.push(DashSegment {
+                        number: i as u64,
+                        uri: self.base_url.to_string(),
+                        url: self.base_url.clone(),
+                        range: Some(range.clone()),
+                        start: scaled(time, index.timescale)
+                            .saturating_sub(scaled(base.presentation_time_offset, base.timescale)),
+                        duration: scaled(*duration, index.timescale),
+                    });
+                    time += duration;
+                }
+                Ok(segments)
+            }
+        }
+    }
+
+    fn template_segments(
+        &self,
+        template: &SegmentTemplate,
+        period: &Period,
+        now: Option<Duration>,
+        depth: Option<Duration>,
+        last_end: Option<Duration>,
+    ) -> Result<Vec<DashSegment>, Error> {
+        let Some(media) = &template.media else {
+            return Err(Error::Mpd(format!(
+                "SegmentTemplate of {} has no media",
+                self.id
+            )));
+        };
+        let timescale = template.timescale;
+        let offset = template.presentation_time_offset;
+        let period_end = period
+            .duration
+            .map(|d| offset + (d.as_secs_f64() * timescale as f64).round() as u64);
+
+        // (number, start time) in timescale units.
+        let mut times: Vec<(u64, u64, u64)> = Vec::new();
+        if !template.timeline.is_empty() {
+            let in_timescale =
+                |d: Duration| offset.saturating_add((d.as_secs_f64() * timescale as f64) as u64);
+            // Segments starting here are past the end of the period or not
+            // produced yet.
+            let limit = match (period_end, now.map(in_timescale)) {
+                (Some(end), Some(now)) => Some(end.min(now)),
+                (end, now) => end.or(now),
+            };
+            let oldest = now
+                .and_then(|now| depth.and_then(|d| now.checked_sub(d)))
+                .map_or(0, in_timescale);
+
+            let mut number = template.start_number;
+            let mut time = 0;
+            for (i, entry) in template.timeline.iter().enumerate() {
+                let duration = entry.duration;
+                if duration == 0 {
+                    return Err(Error::Mpd(format!(
+                        "SegmentTimeline of {} has a segment without duration",
+                        self.id
+                    )));
+                }
+                time = entry.start.unwrap_or(time);
+                let mut count = if entry.repeat >= 0 {
+                    (entry.repeat as u64).saturating_add(1)
+                } else {
+                    // Repeat until the next entry, the end of the period or
+                    // for live streams until now.
+                    let end = template.timeline[i + 1..]
+                        .first()
+                        .and_then(|e| e.start)
+                        .or(limit)
+                        .unwrap_or(time);
+                    end.saturating_sub(time).div_ceil(duration).max(1)
+                };
+
+                if let Some(now) = now {
+                    // Like the segments of a duration, only the ones after
+                    // the last queued one or close to the live edge are
+                    // listed, and never any older than the time shift buffer.
+                    let from = match last_end {
+                        Some(end) => in_timescale(end),
+                        None => in_timescale(now)
+                            .saturating_sub(duration.saturating_mul(LIVE_EDGE_SEGMENTS as u64 + 1)),
+                    }
+                    .max(oldest);
+                    let skip = (from.saturating_sub(time) / duration).min(count);
+                    count -= skip;
+                    number = number.saturating_add(skip);
+                    time = time.saturating_add(skip * duration);
+                }
+                if let Some(limit) = limit {
+                    count = count.min(limit.saturating_sub(time).div_ceil(duration));
+                }
+                if times.len() as u64 + count > MAX_TIMELINE_SEGMENTS {
+                    return Err(Error::Mpd(format!(
+                        "SegmentTimeline of {} lists too many segments",
+                        self.id
+                    )));
+                }
+
+                for _ in 0..count {
+                    times.push((number, time, duration));
+                    number = number.saturating_add(1);
+                    time = time.checked_add(duration).ok_or_else(|| {
+                        Error::Mpd(format!("SegmentTimeline of {} overflows", self.id))
+                    })?;
+                }
+            }
+        } else if let Some(duration) = template.duration.filter(|d| *d > 0) {
+            let duration_secs = duration as f64 / timescale as f64;
+            let (first, last) = match now {
+                Some(now) => {
+                    // A segment is available once all of it has been
+                    // produced.
+                    let available = (now.as_secs_f64() / duration_secs).floor() as u64;
+                    let oldest = depth
+                        .and_then(|d| now.checked_sub(d))
+                        .map_or(0, |start| (start.as_secs_f64() / duration_secs) as u64);
+                    // Only the segments after the last queued one are
+                    // listed, not all of them since the availability start.
+                    let from = match last_end {
+                        Some(end) => (end.as_secs_f64() / duration_secs) as u64,
+                        None => available.saturating_sub(LIVE_EDGE_SEGMENTS as u64),
+                    };
+                    (oldest.max(from), available)
+                }
+                None => {
+                    let total = period.duration.ok_or_else(|| {
+                        Error::Mpd("the length of the presentation is unknown".to_string())
+                    })?;
+                    (0, (total.as_secs_f64() / duration_secs).ceil() as u64)
+                }
+            };
+            for i in first..last {
+                times.push((template.start_number + i, offset + i * duration, duration));
+            }
+        } else {
+            return Err(Error::Mpd(format!(
+                "SegmentTemplate of {} has neither a duration nor a timeline",
+                self.id
+            )));
+        }
+
+        times
+            .into_iter()
+            .filter(|(_, time, duration)| match now {
+                // Timelines of live streams can list segments that are
+                // still being produced.
+                Some(now) => scaled((time + duration).saturating_sub(offset), timescale) <= now,
+                None => true,
+            })
+            .map(|(number, time, duration)| {
+                let uri = self.expand(media, number, time);
+                Ok(DashSegment {
+                    number,
+                    url: segment_url(&self.base_url, &uri)?,
+                    uri,
+                    range: None,
+                    start: scaled(time.saturating_sub(offset), timescale),
+                    duration: scaled(duration, timescale),
+                })
+            })
+            .collect()
+    }
+
+    /// Fills in the identifiers of a `SegmentTemplate` uri.
+    fn expand(&self, template: &str, number: u64, time: u64) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            let Some(len) = rest[start + 1..].find('$') else {
+                rest = &rest[start..];
+                break;
+            };
+            let ident = &rest[start + 1..start + 1 + len];
+            rest = &rest[start + len + 2..];
+
+            let (name, format) = ident.split_once('%').unwrap_or((ident, "d"));
+            // Only integer formats like `%05d` are allowed.
+            let width = format
+                .strip_suffix('d')
+                .and_then(|w| w.trim_start_matches('0').parse::<usize>().ok())
+                .unwrap_or(0);
+            let value = match name {
+                "" => {
+                    out.push('$');
+                    continue;
+                }
+                "RepresentationID" => {
+                    out.push_str(&self.id);
+                    continue;
+                }
+                "Number" => number,
+                "Bandwidth" => self.bandwidth,
+                "Time" => time,
+                _ => {
+                    // Unknown identifiers are left alone.
+                    out.push('$');
+                    out.push_str(ident);
+                    out.push('$');
+                    continue;
+                }
+            };
+            out.push_str(&format!("{:0width$}", value, width = width));
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+impl SegmentTemplate {
+    /// `levels` goes from the most to the least specific element.
+    fn parse(levels: &[Node<'_, '_>]) -> Self {
+        let attr = |name| levels.iter().find_map(|n| n.attribute(name));
+        let number = |name, default| attr(name).and_then(|v| v.parse().ok()).unwrap_or(default);
+        let timeline = levels
+            .iter()
+            .find_map(|n| children(*n, "SegmentTimeline").next())
+            .map(|timeline| {
+                children(timeline, "S")
+                    .map(|s| TimelineEntry {
+                        start: s.attribute("t").and_then(|t| t.parse().ok()),
+                        duration: s.attribute("d").and_then(|d| d.parse().ok()).unwrap_or(0),
+                        repeat: s.attribute("r").and_then(|r| r.parse().ok()).unwrap_or(0),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        SegmentTemplate {
+            media: attr("media").map(str::to_string),
+            initialization: attr("initialization").map(str::to_string),
+            start_number: number("startNumber", 1),
+            timescale: number("timescale", 1).max(1),
+            duration: attr("duration").and_then(|d| d.parse().ok()),
+            presentation_time_offset: number("presentationTimeOffset", 0),
+            timeline,
+        }
+    }
+}
+
+impl SegmentBase {
+    /// `levels` goes from the most to the least specific element.
+    fn parse(levels: &[Node<'_, '_>]) -> Self {
+        let attr = |name| levels.iter().find_map(|n| n.attribute(name));
+        let number = |name, default| attr(name).and_then(|v| v.parse().ok()).unwrap_or(default);
+        SegmentBase {
+            timescale: number("timescale", 1).max(1),
+            presentation_time_offset: number("presentationTimeOffset", 0),
+            initialization: levels
+                .iter()
+                .find_map(|n| children(*n, "Initialization").next())
+                .and_then(|init| init.attribute("range"))
+                .and_then(parse_range),
+            index_range: attr("indexRange").and_then(parse_range),
+        }
+    }
+}
+
+impl SegmentIndex {
+    /// Parses the `sidx` box that was downloaded from `range` of the
+    /// resource.
+    pub(crate) fn parse(data: &[u8], range: &Range<usize>) -> Result<Self, Error> {
+        let invalid = || Error::Mpd("the segment index is not a valid sidx box".to_string());
+        let overflow = || Error::Mpd("the segment index offsets overflow".to_string());
+        let mut reader = BoxReader(data);
+        // The index range may start with other boxes, like `styp`.
+        let (sidx, end) = loop {
+            let offset = data.len() - reader.0.len();
+            let size = reader.u32().ok_or_else(invalid)? as usize;
+            let kind = reader.take(4).ok_or_else(invalid)?;
+            let body = reader.take(size.checked_sub(8).ok_or_else(invalid)?);
+            let body = body.ok_or_else(invalid)?;
+            if kind == b"sidx" {
+                let end = range
+                    .start
+                    .checked_add(offset)
+                    .and_then(|start| start.checked_add(size));
+                break (body, end.ok_or_else(overflow)?);
+            }
+        };
+
+        let mut reader = BoxReader(sidx);
+        let version = reader.take(4).ok_or_else(invalid)?[0];
+        let _reference_id = reader.u32();
+        let timescale = reader.u32().ok_or_else(invalid)?;
+        let (start, first_offset) = if version == 0 {
+            (reader.u32().map(u64::from), reader.u32().map(u64::from))
+        } else {
+            (reader.u64(), reader.u64())
+        };
+        let (start, first_offset) = start.zip(first_offset).ok_or_else(invalid)?;
+        reader.take(2).ok_or_else(invalid)?;
+        let count = reader.take(2).ok_or_else(invalid)?;
+        let count = u16::from_be_bytes([count[0], count[1]]);
+
+        let mut offset = usize::try_from(first_offset)
+            .ok()
+            .and_then(|first| end.checked_add(first))
+            .ok_or_else(overflow)?;
+        let mut segments = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let reference = reader.u32().ok_or_else(invalid)?;
+            let duration = reader.u32().ok_or_else(invalid)?;
+            reader.u32().ok_or_else(invalid)?;
+            if reference & 0x8000_0000 != 0 {
+                return Err(Error::Mpd(
+                    "segment indexes that reference other indexes are not supported".to_string(),
+                ));
+            }
+            let size = (reference & 0x7fff_ffff) as usize;
+            let next = offset.checked_add(size).ok_or_else(overflow)?;
+            segments.push((offset..next, u64::from(duration)));
+            offset = next;
+        }
+
+        Ok(SegmentIndex {
+            start,
+            timescale: u64::from(timescale).max(1),
+            segments,
+        })
+    }
+
+    /// Splits the bytes from `start` to `length` of a resource without a
+    /// segment index into segments of equal size, `duration` is shared
+    /// between them.
+    pub(crate) fn split(start: usize, length: usize, duration: Option<Duration>) -> Self {
+        let size = length.saturating_sub(start);
+        let count = size.div_ceil(SPLIT_SIZE).max(1);
+        let millis = duration.map_or(0, |d| d.as_millis() as u64);
+        let segments = (0..count)
+            .map(|i| {
+                let from = start + i * SPLIT_SIZE;
+                let to = from.saturating_add(SPLIT_SIZE).min(length).max(from);
+                (from..to, millis * (to - from) as u64 / size.max(1) as u64)
+            })
+            .collect();
+        SegmentIndex {
+            start: 0,
+            timescale: 1000,
+            segments,
+        }
+    }
+}
+
+/// Reads the big endian fields of an ISO BMFF box.
+struct BoxReader<'a>(&'a [u8]);
+
+impl<'a> BoxReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from(self.u32()?) << 32 | u64::from(self.u32()?))
+    }
+}
+
+impl SegmentList {
+    /// `levels` goes from the most to the least specific element.
+    fn parse(levels: &[Node<'_, '_>]) -> Self {
+        let attr = |name| levels.iter().find_map(|n| n.attribute(name));
+        let number = |name, default| attr(name).and_then(|v| v.parse().ok()).unwrap_or(default);
+        let initialization = levels
+            .iter()
+            .find_map(|n| children(*n, "Initialization").next())
+            .map(|init| {
+                (
+                    init.attribute("sourceURL").map(str::to_string),
+                    init.attribute("range").and_then(parse_range),
+                )
+            });
+        let segments = levels
+            .iter()
+            .map(|n| {
+                children(*n, "SegmentURL")
+                    .map(|s| {
+                        (
+                            s.attribute("media").map(str::to_string),
+                            s.attribute("mediaRange").and_then(parse_range),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .find(|s| !s.is_empty())
+            .unwrap_or_default();
+
+        SegmentList {
+            start_number: number("startNumber", 1),
+            timescale: number("timescale", 1).max(1),
+            duration: attr("duration").and_then(|d| d.parse().ok()),
+            initialization,
+            segments,
+        }
+    }
+}
+
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+/// Resolves the first `BaseURL` of `node` against the one of its parent.
+fn base_url(node: Node<'_, '_>, parent: &Url) -> Url {
+    children(node, "BaseURL")
+        .next()
+        .and_then(|b| b.text())
+        .and_then(|b| parent.join(b.trim()).ok())
+        .unwrap_or_else(|| parent.clone())
+}
+
+fn scaled(value: u64, timescale: u64) -> Duration {
+    let nanos = value as u128 * 1_000_000_000 / timescale.max(1) as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Parses an `xs:duration` like `PT1H2M3.5S`, years and months are taken as
+/// 365 and 30 days.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim().strip_prefix('P')?;
+    let (date, time) = s.split_once('T').unwrap_or((s, ""));
+    let units: [(&str, &[(char, f64)]); 2] = [
+        (
+            date,
+            &[
+                ('Y', 31_536_000.0),
+                ('M', 2_592_000.0),
+                ('W', 604_800.0),
+                ('D', 86_400.0),
+            ],
+        ),
+        (time, &[('H', 3_600.0), ('M', 60.0), ('S', 1.0)]),
+    ];
+
+    let mut seconds = 0.0;
+    for (mut rest, units) in units {
+        for (unit, scale) in units {
+            if let Some((value, after)) = rest.split_once(*unit) {
+                seconds += value.parse::<f64>().ok()? * scale;
+                rest = after;
+            }
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Parses an `xs:dateTime`, times without a zone are taken as UTC.
+fn parse_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    let s = s.trim();
+    DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_rfc3339(&format!("{}Z", s)))
+        .ok()
+}
+
+/// Parses frame rates like `25` and `30000/1001`.
+fn parse_frame_rate(s: &str) -> Option<f32> {
+    match s.split_once('/') {
+        Some((n, d)) => Some(n.parse::<f32>().ok()? / d.parse::<f32>().ok()?),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses a byte range like `100-199`, the end is inclusive.
+fn parse_range(s: &str) -> Option<Range<usize>> {
+    let (start, end) = s.split_once('-')?;
+    Some(start.trim().parse().ok()?..end.trim().parse::<usize>().ok()? + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(text: &str) -> Manifest {
+        let url = Url::parse("https://example.com/live/manifest.mpd").unwrap();
+        Manifest::parse(text, &url).unwrap()
+    }
+
+    /// The first representation of a manifest with a single period.
+    fn representation(manifest: &Manifest) -> &Representation {
+        &manifest.periods[0].adaptation_sets[0].representations[0]
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(
+            parse_duration("PT1H2M3.5S"),
+            Some(Duration::from_secs_f64(3723.5))
+        );
+        assert_eq!(parse_duration("PT0S"), Some(Duration::ZERO));
+        assert_eq!(parse_duration("P1DT1S"), Some(Duration::from_secs(86_401)));
+        assert_eq!(parse_duration(" PT2S "), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration("PT2X"), None);
+        assert_eq!(parse_duration("1S"), None);
+    }
+
+    #[test]
+    fn expands_templates() {
+        let manifest = manifest(
+            r#"<MPD type="static" mediaPresentationDuration="PT10S"><Period>
+            <AdaptationSet><SegmentTemplate media="a" duration="1"/>
+            <Representation id="v1" bandwidth="800"/></AdaptationSet></Period></MPD>"#,
+        );
+        let rep = representation(&manifest);
+        assert_eq!(
+            rep.expand("$RepresentationID$/$Number$.m4s", 7, 0),
+            "v1/7.m4s"
+        );
+        assert_eq!(rep.expand("seg-$Number%05d$.m4s", 42, 0), "seg-00042.m4s");
+        assert_eq!(rep.expand("$Bandwidth$-$Time$", 0, 9000), "800-9000");
+        assert_eq!(rep.expand("cost$$5-$Number$", 3, 0), "cost$5-3");
+        assert_eq!(rep.expand("$Unknown$-$Number$", 1, 0), "$Unknown$-1");
+    }
+
+    #[test]
+    fn repeats_timeline_until_next_entry() {
+        let manifest = manifest(
+            r#"<MPD type="static" mediaPresentationDuration="PT10S"><Period>
+            <AdaptationSet><SegmentTemplate media="$Time$.m4s" timescale="1000" startNumber="5">
+            <SegmentTimeline><S t="0" d="2000" r="-1"/><S t="6000" d="4000"/></SegmentTimeline>
+            </SegmentTemplate><Representation id="v" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        );
+        let period = &manifest.periods[0];
+        let segments = representation(&manifest)
+            .segments(period, None, None, None, None)
+            .unwrap();
+        let uris: Vec<_> = segments.iter().map(|s| s.uri.as_str()).collect();
+        assert_eq!(uris, ["0.m4s", "2000.m4s", "4000.m4s", "6000.m4s"]);
+        assert_eq!(segments[3].number, 8);
+        assert_eq!(segments[3].start, Duration::from_secs(6));
+        assert_eq!(segments[3].duration, Duration::from_secs(4));
+    }
+
+    #[test]
+    fn repeats_timeline_until_end_of_period() {
+        let manifest = manifest(
+            r#"<MPD type="static" mediaPresentationDuration="PT5S"><Period>
+            <AdaptationSet><SegmentTemplate media="$Number$.m4s">
+            <SegmentTimeline><S d="2" r="-1"/></SegmentTimeline>
+            </SegmentTemplate><Representation id="v" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        );
+        let segments = representation(&manifest)
+            .segments(&manifest.periods[0], None, None, None, None)
+            .unwrap();
+        let uris: Vec<_> = segments.iter().map(|s| s.uri.as_str()).collect();
+        assert_eq!(uris, ["1.m4s", "2.m4s", "3.m4s"]);
+    }
+
+    #[test]
+    fn lists_segment_ranges() {
+        let manifest = manifest(
+            r#"<MPD type="static" mediaPresentationDuration="PT6S"><Period>
+            <AdaptationSet><Representation id="v" bandwidth="1"><BaseURL>media.mp4</BaseURL>
+            <SegmentList timescale="10" duration="30">
+            <Initialization range="0-99"/>
+            <SegmentURL mediaRange="100-199"/><SegmentURL media="other.mp4" mediaRange="200-299"/>
+            </SegmentList></Representation></AdaptationSet></Period></MPD>"#,
+        );
+        let rep = representation(&manifest);
+        let init = rep.init().unwrap().unwrap();
+        assert_eq!(init.url.as_str(), "https://example.com/live/media.mp4");
+        assert_eq!(init.range, Some(0..100));
+
+        let segments = rep
+            .segments(&manifest.periods[0], None, None, None, None)
+            .unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            segments[0].url.as_str(),
+            "https://example.com/live/media.mp4"
+        );
+        assert_eq!(segments[0].range, Some(100..200));
+        assert_eq!(
+            segments[1].url.as_str(),
+            "https://example.com/live/other.mp4"
+        );
+        assert_eq!(segments[1].range, Some(200..300));
+        assert_eq!(segments[1].start, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn starts_live_templates_at_the_edge() {
+        let manifest = manifest(
+            r#"<MPD type="dynamic" availabilityStartTime="2020-01-01T00:00:00Z"><Period start="PT0S">
+            <AdaptationSet><SegmentTemplate media="$Number$.m4s" startNumber="0" duration="2"/>
+            <Representation id="v" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        );
+        let period = &manifest.periods[0];
+        let rep = representation(&manifest);
+        let now = Some(Duration::from_secs(1_000_001));
+
+        let first = rep.segments(period, now, None, None, None).unwrap();
+        let numbers: Vec<_> = first.iter().map(|s| s.number).collect();
+        assert_eq!(numbers, [499_997, 499_998, 499_999]);
+
+        let next = rep
+            .segments(period, now, None, Some(Duration::from_secs(999_996)), None)
+            .unwrap();
+        let numbers: Vec<_> = next.iter().map(|s| s.number).collect();
+        assert_eq!(numbers, [499_998, 499_999]);
+    }
+
+    #[test]
+    fn clamps_live_timelines_to_the_window() {
+        let manifest = manifest(
+            r#"<MPD type="dynamic" availabilityStartTime="2020-01-01T00:00:00Z"><Period start="PT0S">
+            <AdaptationSet><SegmentTemplate media="$Number$.m4s" startNumber="0">
+            <SegmentTimeline><S t="0" d="2" r="1000000000000"/></SegmentTimeline>
+            </SegmentTemplate><Representation id="v" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        );
+        let period = &manifest.periods[0];
+        let rep = representation(&manifest);
+        let now = Some(Duration::from_secs(1_000_001));
+
+        let first = rep.segments(period, now, None, None, None).unwrap();
+        let numbers: Vec<_> = first.iter().map(|s| s.number).collect();
+        // One more than the live edge needs, the watcher keeps the last ones.
+        assert_eq!(numbers, [499_996, 499_997, 499_998, 499_999]);
+
+        let next = rep
+            .segments(period, now, None, Some(Duration::from_secs(999_996)), None)
+            .unwrap();
+        let numbers: Vec<_> = next.iter().map(|s| s.number).collect();
+        assert_eq!(numbers, [499_998, 499_999]);
+    }
+
+    #[test]
+    fn rejects_timeline_segments_without_duration() {
+        let manifest = manifest(
+            r#"<MPD type="static" mediaPresentationDuration="PT5S"><Period>
+            <AdaptationSet><SegmentTemplate media="$Number$.m4s">
+            <SegmentTimeline><S d="0" r="-1"/></SegmentTimeline>
+            </SegmentTemplate><Representation id="v" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+        );
+        let segments =
+            representation(&manifest).segments(&manifest.periods[0], None, None, None, None);
+        assert!(matches!(segments, Err(Error::Mpd(_))));
+    }
+
+    /// A version 1 `sidx` box with one reference of `size` bytes.
+    fn sidx(first_offset: u64, size: u32) -> Vec<u8> {
+        let mut body = vec![1, 0, 0, 0];
+        body.extend_from_slice(&1u32.to_be_bytes());
+        body.extend_from_slice(&1000u32.to_be_bytes());
+        body.extend_from_slice(&0u64.to_be_bytes());
+        body.extend_from_slice(&first_offset.to_be_bytes());
+        body.extend_from_slice(&[0, 0, 0, 1]);
+        body.extend_from_slice(&size.to_be_bytes());
+        body.extend_from_slice(&2000u32.to_be_bytes());
+        body.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut data = (body.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend_from_slice(b"sidx");
+        data.extend_from_slice(&body);
+        data
+    }
+
+    #[test]
+    fn parses_segment_index() {
+        let data = sidx(10, 500);
+        let index = SegmentIndex::parse(&data, &(100..100 + data.len())).unwrap();
+        let end = 100 + data.len() + 10;
+        assert_eq!(index.segments, [(end..end + 500, 2000)]);
+
+        let overflow = SegmentIndex::parse(&data, &(usize::MAX - 10..usize::MAX));
+        assert!(matches!(overflow, Err(Error::Mpd(_))));
+        let data = sidx(u64::MAX, 500);
+        let overflow = SegmentIndex::parse(&data, &(0..data.len()));
+        assert!(matches!(overflow, Err(Error::Mpd(_))));
+    }
+
+    #[test]
+    fn splits_resources_after_the_init_section() {
+        let manifest = manifest(
+            r#"<MPD type="static" mediaPresentationDuration="PT10S"><Period>
+            <AdaptationSet><Representation id="v" bandwidth="1"><BaseURL>media.mp4</BaseURL>
+            <SegmentBase><Initialization range="0-999"/></SegmentBase>
+            </Representation></AdaptationSet></Period></MPD>"#,
+        );
+        let rep = representation(&manifest);
+        let init = rep.init().unwrap().unwrap();
+        assert_eq!(init.range, Some(0..1000));
+
+        let Addressing::Base(base) = &rep.addressing else {
+            panic!("not a SegmentBase");
+        };
+        let start = base.initialization.as_ref().unwrap().end;
+        let length = start + SPLIT_SIZE + 500;
+        let index = SegmentIndex::split(start, length, Some(Duration::from_secs(10)));
+        let ranges: Vec<_> = index.segments.iter().map(|(r, _)| r.clone()).collect();
+        assert_eq!(ranges, [1000..1000 + SPLIT_SIZE, 1000 + SPLIT_SIZE..length]);
+    }
+}
@@ -1,18 +1,235 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Poll, Waker},
+};
+
 use bytes::Bytes;
 use futures_core::stream::Stream;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::{
+    sync::{mpsc::error::SendError, Notify},
+    task::JoinHandle,
+};
+
+use crate::stats::StatsHandle;
+
+/// How much downloaded data a [`DownloadStream`] may hold before the
+/// download is slowed down to the pace of the consumer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BufferSize {
+    /// Never slow down the download.
+    #[default]
+    Unbounded,
+    /// Buffer at most this many segments, for chunked streams every
+    /// chunk counts as a segment.
+    Segments(usize),
+    /// Buffer at most this many bytes.
+    Bytes(usize),
+}
+
+/// What to do when the buffer of a [`DownloadStream`] is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Wait for the consumer to catch up.
+    #[default]
+    Block,
+    /// Drop the oldest buffered segments to make room for the new one and
+    /// emit [`Event::Dropped`] where they were.
+    ///
+    /// Only whole HLS and DASH segments are dropped and their init section is
+    /// kept, since the segments after them need it. Chunked downloads have
+    /// no segments, so they wait for the consumer like [`Overflow::Block`].
+    DropOldest,
+}
 
 /// This struct implments a stream that is used to
 /// received data from chunked and hls streams.
+///
+/// The download runs in background tasks that are aborted when the stream
+/// is dropped or [`DownloadStream::cancel`] is called.
 #[derive(Debug)]
 pub struct DownloadStream {
-    rx: UnboundedReceiver<Event>,
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+    stats: StatsHandle,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified every time an event is taken out of the buffer.
+    space: Notify,
+}
+
+#[derive(Debug)]
+struct State {
+    queue: VecDeque<Queued>,
+    /// Number of `Event::Bytes` in the queue.
+    segments: usize,
+    /// Number of bytes in the queue.
+    bytes: usize,
+    senders: usize,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Queued {
+    event: Event,
+    /// The bytes are the init section of a segment.
+    init: bool,
+}
+
+impl State {
+    fn has_room(&self, size: BufferSize, len: usize) -> bool {
+        // Always let a single segment through even if it is larger than the
+        // buffer, otherwise the stream would never make progress.
+        if self.segments == 0 {
+            return true;
+        }
+        match size {
+            BufferSize::Unbounded => true,
+            BufferSize::Segments(max) => self.segments < max,
+            BufferSize::Bytes(max) => self.bytes + len <= max,
+        }
+    }
+
+    /// The position of the `SegmentStart` and `SegmentEnd` of the oldest
+    /// segment that is buffered whole.
+    fn oldest_segment(&self) -> Option<(usize, usize)> {
+        let start = self
+            .queue
+            .iter()
+            .position(|q| matches!(q.event, Event::SegmentStart { .. }))?;
+        let end = start
+            + self
+                .queue
+                .iter()
+                .skip(start)
+                .position(|q| matches!(q.event, Event::SegmentEnd { .. }))?;
+        Some((start, end))
+    }
+
+    /// Removes the oldest whole segments until there is room for `len`
+    /// bytes, returns `false` if there are none left to remove.
+    fn drop_oldest(&mut self, size: BufferSize, len: usize) -> bool {
+        while !self.has_room(size, len) {
+            let Some((start, end)) = self.oldest_segment() else {
+                return false;
+            };
+
+            let mut bytes = 0;
+            let mut inits = Vec::new();
+            for queued in self.queue.drain(start..=end) {
+                match &queued.event {
+                    Event::Bytes { .. } if queued.init => inits.push(queued),
+                    Event::Bytes { bytes: b } => {
+                        self.segments -= 1;
+                        self.bytes -= b.len();
+                        bytes += b.len();
+                    }
+                    _ => {}
+                }
+            }
+
+            // Merge with the marker of a previous drop if there is one.
+            let mut idx = start;
+            match start
+                .checked_sub(1)
+                .and_then(|prev| self.queue.get_mut(prev))
+            {
+                Some(Queued {
+                    event:
+                        Event::Dropped {
+                            segments,
+                            bytes: dropped,
+                        },
+                    ..
+                }) => {
+                    *segments += 1;
+                    *dropped += bytes;
+                }
+                _ => {
+                    let event = Event::Dropped { segments: 1, bytes };
+                    self.queue.insert(idx, Queued { event, init: false });
+                    idx += 1;
+                }
+            }
+            // The init section stays for the segments that follow.
+            for init in inits {
+                self.queue.insert(idx, init);
+                idx += 1;
+            }
+        }
+        true
+    }
 }
 
 impl DownloadStream {
-    pub(crate) fn new() -> (Self, UnboundedSender<Event>) {
-        let (tx, rx) = unbounded_channel();
-        (DownloadStream { rx }, tx)
+    pub(crate) fn new(size: BufferSize, overflow: Overflow) -> (Self, EventSender) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                segments: 0,
+                bytes: 0,
+                senders: 1,
+                closed: false,
+                waker: None,
+            }),
+            space: Notify::new(),
+        });
+        (
+            DownloadStream {
+                shared: shared.clone(),
+                tasks: Vec::new(),
+                stats: StatsHandle::new(),
+            },
+            EventSender {
+                shared,
+                size,
+                overflow,
+            },
+        )
+    }
+}
+
+impl DownloadStream {
+    /// Runs `task` in the background for as long as the download lasts.
+    pub(crate) fn spawn<F>(&mut self, task: F)
+    where
+        F: Future + Send + 'static,
+    {
+        self.tasks.push(tokio::task::spawn(async move {
+            task.await;
+        }));
+    }
+
+    /// A handle to the statistics of the download, which can be read while
+    /// the stream is consumed.
+    pub fn stats(&self) -> StatsHandle {
+        self.stats.clone()
+    }
+
+    /// Stops the download.
+    ///
+    /// The background tasks are aborted, so no more playlists or segments
+    /// are requested, and the stream ends after the events that were
+    /// already received.
+    pub fn cancel(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+
+    /// Cancels the download and waits until all of its background tasks
+    /// have stopped and released their connections.
+    pub async fn shutdown(mut self) {
+        self.cancel();
+        for task in std::mem::take(&mut self.tasks) {
+            // The tasks were aborted, so the only error is the cancellation.
+            let _ = task.await;
+        }
     }
 }
 
@@ -20,19 +237,186 @@ impl Stream for DownloadStream {
     type Item = Event;
 
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.rx.poll_recv(cx)
+        let mut state = self.shared.lock();
+        match state.queue.pop_front().map(|q| q.event) {
+            Some(event) => {
+                if let Event::Bytes { bytes } = &event {
+                    state.segments -= 1;
+                    state.bytes -= bytes.len();
+                }
+                drop(state);
+                self.shared.space.notify_waiters();
+                Poll::Ready(Some(event))
+            }
+            None if state.senders == 0 => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
     }
 }
 
+impl Drop for DownloadStream {
+    fn drop(&mut self) {
+        self.cancel();
+        self.shared.lock().closed = true;
+        self.shared.space.notify_waiters();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The sending half of a [`DownloadStream`].
+#[derive(Debug)]
+pub(crate) struct EventSender {
+    shared: Arc<Shared>,
+    size: BufferSize,
+    overflow: Overflow,
+}
+
+impl EventSender {
+    /// Sends an event to the stream, waiting for room in the buffer if the
+    /// overflow policy is [`Overflow::Block`].
+    pub(crate) async fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        self.push(event, false).await
+    }
+
+    /// Sends a downloaded segment, with its init section if it needs one.
+    pub(crate) async fn send_segment(
+        &self,
+        info: crate::SegmentInfo,
+        init: Option<Bytes>,
+        bytes: Bytes,
+    ) -> Result<(), SendError<Event>> {
+        let sequence = info.sequence;
+        self.push(Event::SegmentStart { info }, false).await?;
+        if let Some(bytes) = init {
+            self.push(Event::Bytes { bytes }, true).await?;
+        }
+        if !bytes.is_empty() {
+            self.push(Event::Bytes { bytes }, false).await?;
+        }
+        self.push(Event::SegmentEnd { sequence }, false).await
+    }
+
+    async fn push(&self, event: Event, init: bool) -> Result<(), SendError<Event>> {
+        let len = match &event {
+            Event::Bytes { bytes } => Some(bytes.len()),
+            _ => None,
+        };
+
+        loop {
+            // Register for notifications before looking at the buffer, so
+            // space freed in between is not missed.
+            let mut notified = std::pin::pin!(self.shared.space.notified());
+            notified.as_mut().enable();
+
+            {
+                let mut state = self.shared.lock();
+                if state.closed {
+                    return Err(SendError(event));
+                }
+
+                let room = match len {
+                    Some(len) if !state.has_room(self.size, len) => match self.overflow {
+                        Overflow::Block => false,
+                        Overflow::DropOldest => state.drop_oldest(self.size, len),
+                    },
+                    _ => true,
+                };
+
+                if room {
+                    if let Some(len) = len {
+                        state.segments += 1;
+                        state.bytes += len;
+                    }
+                    state.queue.push_back(Queued { event, init });
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                    return Ok(());
+                }
+            }
+
+            notified.await;
+        }
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        EventSender {
+            shared: self.shared.clone(),
+            size: self.size,
+            overflow: self.overflow,
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// An event of a [`DownloadStream`].
+///
+/// New kinds of events may be added, so matches need a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Event {
     /// Bytes to be written to
     Bytes {
         bytes: Bytes,
     },
+    /// The bytes of a new HLS segment follow, including its init section if
+    /// it needs one.
+    SegmentStart {
+        info: crate::SegmentInfo,
+    },
+    /// All bytes of the segment with this sequence number have been sent.
+    SegmentEnd {
+        sequence: usize,
+    },
+    /// Segments were dropped because the consumer could not keep up, see
+    /// [`Overflow::DropOldest`].
+    Dropped {
+        segments: usize,
+        bytes: usize,
+    },
+    /// The live playlist moved past segments before they could be
+    /// downloaded, so the recording is missing them. The duration is
+    /// estimated from the average segment duration of the playlist.
+    Gap {
+        missing_segments: usize,
+        missing_duration: std::time::Duration,
+    },
+    /// A segment could not be downloaded, even after retrying, and is
+    /// missing from the output.
+    SegmentFailed {
+        url: reqwest::Url,
+        error: crate::Error,
+    },
+    /// The length of the whole download in bytes, sent before the first
+    /// bytes of chunked downloads when the server tells it.
+    ContentLength {
+        total: u64,
+    },
     End,
     Error {
         error: crate::Error,
@@ -1,12 +1,17 @@
+use aes::cipher::block_padding::UnpadError;
 use hls_m3u8::Error as HlsError;
-use reqwest::Error as ReqwestError;
+use reqwest::{Error as ReqwestError, StatusCode, Url};
 use std::{
     error::Error as StdError,
     fmt::{Display, Formatter, Result as FmtResult},
+    time::Duration,
 };
 use tokio::io::Error as TokioIoError;
 use url::ParseError;
 
+/// How much of a playlist is kept in [`Error::Playlist`].
+const SNIPPET_LENGTH: usize = 512;
+
 #[derive(Debug)]
 pub enum Error {
     /// M3U8 error
@@ -15,8 +20,136 @@ pub enum Error {
     Reqwest(ReqwestError),
     /// Url error.
     Url(ParseError),
+    /// A uri in a playlist could not be resolved against the playlist url.
+    Uri { uri: String, error: ParseError },
     /// Tokio IO error
     TIO(TokioIoError),
+    /// The AES-128 key was not 16 bytes long.
+    KeyLength(usize),
+    /// Segment decryption failed.
+    Decrypt(UnpadError),
+    /// The DASH manifest is not valid XML.
+    Xml(roxmltree::Error),
+    /// The DASH manifest could not be understood.
+    Mpd(String),
+    /// The http-flv stream could not be understood.
+    Flv(String),
+    /// A playlist or manifest could not be parsed, `source` is the
+    /// [`Error::Hls`], [`Error::Xml`] or [`Error::Mpd`] that says why.
+    Playlist {
+        url: Url,
+        /// The beginning of the playlist.
+        snippet: String,
+        source: Box<Error>,
+    },
+    /// The server sent nothing for longer than the timeout.
+    Timeout(Url),
+    /// The connection ended before the end of the resource, or a live
+    /// stream ended.
+    ConnectionClosed(Url),
+    /// The resource changed while it was downloaded, so the download could
+    /// not be resumed.
+    ResourceChanged(Url),
+    /// The server answered with an unsuccessful status.
+    Status {
+        url: Url,
+        status: StatusCode,
+        /// How long the server asked us to wait before retrying.
+        retry_after: Option<Duration>,
+    },
+    /// The [`DownloadStream`](crate::DownloadStream) was stopped or dropped
+    /// while the download was running.
+    Stopped,
+}
+
+/// What can be done about an [`Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Caused by the network or the server, trying again later may work.
+    Transient,
+    /// The server rejected the url with 401, 403 or 410, a new url is needed,
+    /// see [`RequestRefresh`](crate::RequestRefresh).
+    Expired,
+    /// Trying again will fail the same way.
+    Fatal,
+}
+
+impl Error {
+    /// Wraps an error from parsing the playlist `text` downloaded from `url`.
+    pub(crate) fn playlist(url: &Url, text: &str, source: Error) -> Self {
+        let mut end = text.len().min(SNIPPET_LENGTH);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Error::Playlist {
+            url: url.clone(),
+            snippet: text[..end].to_string(),
+            source: Box::new(source),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        let status = |status: StatusCode| match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::GONE => {
+                ErrorKind::Expired
+            }
+            status
+                if status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::REQUEST_TIMEOUT =>
+            {
+                ErrorKind::Transient
+            }
+            _ => ErrorKind::Fatal,
+        };
+
+        match self {
+            Error::Status { status: s, .. } => status(*s),
+            Error::Reqwest(error) => match error.status() {
+                Some(s) => status(s),
+                // A connection closed in the middle of the body is a decode
+                // error.
+                None if error.is_timeout()
+                    || error.is_connect()
+                    || error.is_body()
+                    || error.is_decode() =>
+                {
+                    ErrorKind::Transient
+                }
+                None => ErrorKind::Fatal,
+            },
+            Error::TIO(_) | Error::Timeout(_) | Error::ConnectionClosed(_) => ErrorKind::Transient,
+            _ => ErrorKind::Fatal,
+        }
+    }
+
+    /// Returns if the error may go away on its own.
+    pub fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+
+    /// The url of the playlist, segment or stream the error happened with,
+    /// if it is known.
+    pub fn url(&self) -> Option<&Url> {
+        match self {
+            Error::Reqwest(error) => error.url(),
+            Error::Playlist { url, .. }
+            | Error::Timeout(url)
+            | Error::ConnectionClosed(url)
+            | Error::ResourceChanged(url)
+            | Error::Status { url, .. } => Some(url),
+            _ => None,
+        }
+    }
+
+    /// The http status the server answered with.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Status { status, .. } => Some(*status),
+            Error::Reqwest(error) => error.status(),
+            _ => None,
+        }
+    }
 }
 
 impl From<HlsError> for Error {
@@ -37,6 +170,12 @@ impl From<ParseError> for Error {
     }
 }
 
+impl From<roxmltree::Error> for Error {
+    fn from(err: roxmltree::Error) -> Self {
+        Error::Xml(err)
+    }
+}
+
 impl From<TokioIoError> for Error {
     fn from(err: TokioIoError) -> Self {
         Error::TIO(err)
@@ -58,12 +197,56 @@ impl Display for Error {
                 f.write_str("Url Error: ")?;
                 Display::fmt(url, f)
             }
+            Error::Uri { uri, error } => {
+                write!(f, "Url Error: could not resolve {:?}: {}", uri, error)
+            }
             Error::TIO(io) => {
                 f.write_str("Tokio IO Error: ")?;
                 Display::fmt(io, f)
             }
+            Error::KeyLength(len) => {
+                write!(f, "Key Error: expected a 16 byte key, got {} bytes", len)
+            }
+            Error::Decrypt(unpad) => {
+                f.write_str("Decrypt Error: ")?;
+                Display::fmt(unpad, f)
+            }
+            Error::Xml(xml) => {
+                f.write_str("Xml Error: ")?;
+                Display::fmt(xml, f)
+            }
+            Error::Mpd(reason) => write!(f, "Mpd Error: {}", reason),
+            Error::Flv(reason) => write!(f, "Flv Error: {}", reason),
+            Error::Playlist { url, source, .. } => {
+                write!(f, "Playlist Error: {} could not be parsed: {}", url, source)
+            }
+            Error::Timeout(url) => {
+                write!(f, "Timeout Error: nothing received from {} in time", url)
+            }
+            Error::ConnectionClosed(url) => {
+                write!(f, "Connection Error: {} closed before the end", url)
+            }
+            Error::ResourceChanged(url) => {
+                write!(f, "Resume Error: {} changed while it was downloaded", url)
+            }
+            Error::Status { url, status, .. } => {
+                write!(f, "Http Error: {} for {}", status, url)
+            }
+            Error::Stopped => f.write_str("Stopped: the download was stopped"),
         }
     }
 }
 
-impl StdError for Error {}
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Hls(error) => Some(error),
+            Error::Reqwest(error) => Some(error),
+            Error::Url(error) | Error::Uri { error, .. } => Some(error),
+            Error::TIO(error) => Some(error),
+            Error::Xml(error) => Some(error),
+            Error::Playlist { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
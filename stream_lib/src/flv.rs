@@ -0,0 +1,274 @@
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, warn};
+
+use crate::error::Error;
+
+/// The `FLV` signature, version, flags and header length.
+const SIGNATURE: &[u8] = b"FLV";
+/// Type, data size, timestamp and stream id.
+const TAG_HEADER_LEN: usize = 11;
+/// The size of the previous tag follows every tag.
+const TAG_SIZE_LEN: usize = 4;
+/// The header is 9 bytes, longer ones are allowed for future versions but
+/// not without limit, as it is buffered whole.
+const MAX_HEADER_LEN: usize = 4096;
+
+const AUDIO: u8 = 8;
+const VIDEO: u8 = 9;
+const SCRIPT: u8 = 18;
+
+/// Joins the http-flv responses of several connections to the same live
+/// stream into one flv file.
+///
+/// Every connection starts with the flv header, the `onMetaData` script tag
+/// and the codec sequence headers, these are only kept from the first one.
+/// Sequence headers that changed are kept, as the stream needs them to
+/// decode what follows. Connections that restart their timestamps are
+/// moved to continue after the last tag.
+pub(crate) struct FlvStitcher {
+    /// Bytes of an incomplete tag.
+    buf: BytesMut,
+    /// Number of connections so far.
+    connections: usize,
+    /// Whether the header of the current connection has been read.
+    header: bool,
+    /// The stream is not flv, so it is passed on as is.
+    passthrough: bool,
+    video_config: Option<Bytes>,
+    audio_config: Option<Bytes>,
+    /// Timestamp of the last audio or video tag written.
+    last_timestamp: u32,
+    /// Added to the timestamps of the current connection, set by its first
+    /// audio or video tag.
+    offset: Option<u32>,
+}
+
+impl FlvStitcher {
+    pub(crate) fn new() -> Self {
+        FlvStitcher {
+            buf: BytesMut::new(),
+            connections: 0,
+            header: false,
+            passthrough: false,
+            video_config: None,
+            audio_config: None,
+            last_timestamp: 0,
+            offset: None,
+        }
+    }
+
+    /// The bytes of a new connection follow.
+    pub(crate) fn start_connection(&mut self) {
+        if !self.buf.is_empty() {
+            debug!(
+                "[FLV] Drops {} bytes of a tag cut off by the reconnect.",
+                self.buf.len()
+            );
+            self.buf.clear();
+        }
+        self.connections += 1;
+        self.header = false;
+        self.offset = None;
+    }
+
+    /// Returns the bytes to write for `bytes` of the current connection.
+    pub(crate) fn push(&mut self, bytes: Bytes) -> Result<Bytes, Error> {
+        if self.passthrough {
+            return Ok(bytes);
+        }
+        self.buf.extend_from_slice(&bytes);
+
+        let mut out = BytesMut::new();
+        if !self.header {
+            if self.buf.len() < SIGNATURE.len() {
+                return Ok(Bytes::new());
+            }
+            if !self.buf.starts_with(SIGNATURE) {
+                if self.connections > 1 {
+                    warn!("[FLV] The stream did not restart with a flv header, it is kept as is.");
+                }
+                self.passthrough = true;
+                return Ok(self.buf.split().freeze());
+            }
+            // The header length is followed by the size of the tag before
+            // the first one, which is always 0.
+            let Some(len) = self.buf.get(5..9) else {
+                return Ok(Bytes::new());
+            };
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            if len > MAX_HEADER_LEN {
+                return Err(Error::Flv(format!(
+                    "the header claims to be {} bytes long",
+                    len
+                )));
+            }
+            let len = len + TAG_SIZE_LEN;
+            if self.buf.len() < len {
+                return Ok(Bytes::new());
+            }
+            let header = self.buf.split_to(len);
+            if self.connections == 1 {
+                out.extend_from_slice(&header);
+            }
+            self.header = true;
+        }
+
+        while let Some(tag) = self.next_tag() {
+            self.write_tag(tag, &mut out);
+        }
+        Ok(out.freeze())
+    }
+
+    fn next_tag(&mut self) -> Option<BytesMut> {
+        let header = self.buf.get(..TAG_HEADER_LEN)?;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let len = TAG_HEADER_LEN + size + TAG_SIZE_LEN;
+        (self.buf.len() >= len).then(|| self.buf.split_to(len))
+    }
+
+    fn write_tag(&mut self, mut tag: BytesMut, out: &mut BytesMut) {
+        let first = self.connections == 1;
+        // The upper bits are the filter flag and reserved.
+        let kind = tag[0] & 0x1f;
+        match kind {
+            SCRIPT if !first => return,
+            AUDIO | VIDEO => {}
+            _ => {
+                out.extend_from_slice(&tag);
+                return;
+            }
+        }
+
+        let timestamp = u32::from_be_bytes([tag[7], tag[4], tag[5], tag[6]]);
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => {
+                let offset = match first {
+                    true => 0,
+                    false => self.last_timestamp.saturating_sub(timestamp),
+                };
+                if offset > 0 {
+                    debug!(
+                        "[FLV] Moves the timestamps of the reconnect by {}ms.",
+                        offset
+                    );
+                }
+                *self.offset.insert(offset)
+            }
+        };
+
+        let data = &tag[TAG_HEADER_LEN..tag.len() - TAG_SIZE_LEN];
+        if is_sequence_header(kind, data) {
+            let config = match kind {
+                VIDEO => &mut self.video_config,
+                _ => &mut self.audio_config,
+            };
+            if config.as_deref() == Some(data) {
+                return;
+            }
+            *config = Some(Bytes::copy_from_slice(data));
+        }
+
+        let timestamp = timestamp.wrapping_add(offset);
+        let [ext, high, mid, low] = timestamp.to_be_bytes();
+        tag[4..8].copy_from_slice(&[high, mid, low, ext]);
+        self.last_timestamp = timestamp;
+        out.extend_from_slice(&tag);
+    }
+}
+
+/// Whether `data` of an audio or video tag holds the decoder configuration.
+fn is_sequence_header(kind: u8, data: &[u8]) -> bool {
+    match (kind, data) {
+        // Enhanced flv, the packet type is in the lower bits.
+        (VIDEO, [flags, ..]) if flags & 0x80 != 0 => flags & 0x0f == 0,
+        // AVC or HEVC with the AVCPacketType of a sequence header.
+        (VIDEO, [flags, packet, ..]) => matches!(flags & 0x0f, 7 | 12) && *packet == 0,
+        // AAC with the AACPacketType of a sequence header.
+        (AUDIO, [flags, packet, ..]) => flags >> 4 == 10 && *packet == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(data_offset: u32) -> Vec<u8> {
+        let mut header = b"FLV\x01\x05".to_vec();
+        header.extend_from_slice(&data_offset.to_be_bytes());
+        header.extend_from_slice(&[0; TAG_SIZE_LEN]);
+        header
+    }
+
+    fn tag(kind: u8, timestamp: u32, data: &[u8]) -> Vec<u8> {
+        let [ext, high, mid, low] = timestamp.to_be_bytes();
+        let size = (data.len() as u32).to_be_bytes();
+        let mut tag = vec![
+            kind, size[1], size[2], size[3], high, mid, low, ext, 0, 0, 0,
+        ];
+        tag.extend_from_slice(data);
+        tag.extend_from_slice(&((TAG_HEADER_LEN + data.len()) as u32).to_be_bytes());
+        tag
+    }
+
+    /// The header, metadata and AVC sequence header every connection starts
+    /// with.
+    fn connection_start() -> Vec<u8> {
+        [
+            header(9),
+            tag(SCRIPT, 0, b"onMetaData"),
+            tag(VIDEO, 0, &[0x17, 0, 1, 2]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn joins_reconnects() {
+        let mut flv = FlvStitcher::new();
+        flv.start_connection();
+        let first = [connection_start(), tag(VIDEO, 1000, &[0x27, 1, 9])].concat();
+        // Split in the middle of the header and of a tag.
+        let out = [
+            flv.push(Bytes::copy_from_slice(&first[..7])).unwrap(),
+            flv.push(Bytes::copy_from_slice(&first[7..30])).unwrap(),
+            flv.push(Bytes::copy_from_slice(&first[30..])).unwrap(),
+        ]
+        .concat();
+        assert_eq!(out, first);
+
+        // The reconnect starts its timestamps from 0 again.
+        flv.start_connection();
+        let second = [connection_start(), tag(VIDEO, 40, &[0x27, 1, 8])].concat();
+        let out = flv.push(Bytes::from(second)).unwrap();
+        assert_eq!(out, tag(VIDEO, 1040, &[0x27, 1, 8]));
+    }
+
+    #[test]
+    fn keeps_changed_sequence_headers() {
+        let mut flv = FlvStitcher::new();
+        flv.start_connection();
+        flv.push(Bytes::from(connection_start())).unwrap();
+
+        flv.start_connection();
+        let second = [header(9), tag(VIDEO, 0, &[0x17, 0, 3, 4])].concat();
+        let out = flv.push(Bytes::from(second)).unwrap();
+        assert_eq!(out, tag(VIDEO, 0, &[0x17, 0, 3, 4]));
+    }
+
+    #[test]
+    fn passes_other_streams_through() {
+        let mut flv = FlvStitcher::new();
+        flv.start_connection();
+        let out = flv.push(Bytes::from_static(b"\x47\x40\x00")).unwrap();
+        assert_eq!(out, &b"\x47\x40\x00"[..]);
+    }
+
+    #[test]
+    fn rejects_huge_headers() {
+        let mut flv = FlvStitcher::new();
+        flv.start_connection();
+        let out = flv.push(Bytes::from(header(u32::MAX)));
+        assert!(matches!(out, Err(Error::Flv(_))));
+    }
+}
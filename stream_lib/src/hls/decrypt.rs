@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use bytes::Bytes;
+use hls_m3u8::types::{DecryptionKey, EncryptionMethod, KeyFormat};
+use reqwest::{header::HeaderMap, Client, Url};
+use tracing::{debug, warn};
+
+use crate::{error::Error, retry::check_status};
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// The key and IV needed to decrypt an AES-128 encrypted segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentKey {
+    pub url: Url,
+    pub iv: [u8; 16],
+}
+
+impl SegmentKey {
+    /// Finds the AES-128 key among the keys that apply to a segment or an
+    /// init section, if any.
+    ///
+    /// The IV is either the one given in the `EXT-X-KEY` tag or, when it is
+    /// missing, the media sequence `number` of the segment.
+    pub(crate) fn from_keys(
+        playlist_url: &Url,
+        keys: Vec<&DecryptionKey<'_>>,
+        number: usize,
+    ) -> Result<Option<Self>, Error> {
+        for key in keys {
+            if key.format.is_some() && key.format != Some(KeyFormat::Identity) {
+                continue;
+            }
+
+            if key.method != EncryptionMethod::Aes128 {
+                warn!(
+                    "[HLS] Segment uses unsupported encryption {}, it will not be decrypted.",
+                    key.method
+                );
+                return Ok(None);
+            }
+
+            let iv = match key.iv.to_slice() {
+                Some(iv) => iv,
+                None => (number as u128).to_be_bytes(),
+            };
+
+            return Ok(Some(SegmentKey {
+                url: super::segment_url(playlist_url, key.uri())?,
+                iv,
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Number of keys kept around, segments downloaded in parallel may straddle
+/// a key rotation.
+const KEY_CACHE_SIZE: usize = 4;
+
+/// Caches the last fetched keys, so a key is only downloaded again when the
+/// playlist rotates to a new key uri.
+#[derive(Debug, Default)]
+pub(crate) struct KeyCache {
+    keys: VecDeque<(Url, [u8; 16])>,
+}
+
+impl KeyCache {
+    pub(crate) async fn get(
+        &mut self,
+        http: &Client,
+        headers: &HeaderMap,
+        url: &Url,
+        timeout: std::time::Duration,
+    ) -> Result<[u8; 16], Error> {
+        if let Some((_, key)) = self.keys.iter().find(|(cached_url, _)| cached_url == url) {
+            return Ok(*key);
+        }
+
+        debug!("[HLS] Fetching key {}", url);
+        let bytes = http
+            .get(url.clone())
+            .headers(headers.clone())
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(Error::from)
+            .and_then(check_status)?
+            .bytes()
+            .await?;
+
+        let key: [u8; 16] = bytes
+            .as_ref()
+            .try_into()
+            .map_err(|_| Error::KeyLength(bytes.len()))?;
+
+        if self.keys.len() == KEY_CACHE_SIZE {
+            self.keys.pop_front();
+        }
+        self.keys.push_back((url.clone(), key));
+        Ok(key)
+    }
+}
+
+/// Decrypts a full AES-128-CBC encrypted segment with PKCS7 padding.
+pub(crate) fn decrypt(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> Result<Bytes, Error> {
+    let mut buf = data.to_vec();
+    let len = Aes128CbcDec::new(key.into(), iv.into())
+        .decrypt_padded_mut::<Pkcs7>(&mut buf)
+        .map_err(Error::Decrypt)?
+        .len();
+    buf.truncate(len);
+    Ok(Bytes::from(buf))
+}
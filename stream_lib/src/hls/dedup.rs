@@ -0,0 +1,157 @@
+use std::{collections::VecDeque, time::Duration};
+
+use hls_m3u8::{MediaPlaylist, MediaSegment};
+use patricia_tree::PatriciaSet;
+use tracing::debug;
+
+use super::segment_id;
+
+/// How many segment ids are remembered for playlists with broken media
+/// sequence numbers.
+const ID_WINDOW: usize = 1024;
+
+/// Keeps track of which segments of a live playlist have already been queued.
+///
+/// Segments are identified by their media sequence number, anything newer
+/// than the last seen number is new. The ids of the most recent segments are
+/// kept as a fallback for playlists where the numbers can not be trusted,
+/// e.g. when they are missing or the stream restarts from 0.
+pub(crate) struct SeenSegments {
+    last_sequence: Option<usize>,
+    ids: PatriciaSet,
+    order: VecDeque<String>,
+}
+
+impl SeenSegments {
+    pub(crate) fn new() -> Self {
+        SeenSegments {
+            last_sequence: None,
+            ids: PatriciaSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns how many segments slid out of the live window between the last
+    /// playlist and `playlist` without being seen, and roughly how long they
+    /// were based on the average segment duration.
+    ///
+    /// Must be called before [`SeenSegments::new_segments`].
+    pub(crate) fn missing(&self, playlist: &MediaPlaylist<'_>) -> Option<(usize, Duration)> {
+        let numbers = playlist.segments.iter().map(|(_, s)| s.number());
+        let missing = match (self.last_sequence, numbers.min()) {
+            (Some(last), Some(first)) if first > last + 1 => first - last - 1,
+            _ => return None,
+        };
+
+        let total: Duration = playlist
+            .segments
+            .iter()
+            .map(|(_, s)| s.duration.duration())
+            .sum();
+        let average = total / playlist.segments.num_elements() as u32;
+        Some((missing, average * missing as u32))
+    }
+
+    /// Returns the segments of `playlist` which have not been seen before, in
+    /// playlist order.
+    pub(crate) fn new_segments<'a, 'b>(
+        &mut self,
+        playlist: &'a MediaPlaylist<'b>,
+    ) -> Vec<&'a MediaSegment<'b>> {
+        let segments: Vec<_> = playlist.segments.iter().map(|(_, s)| s).collect();
+        let Some(max) = segments.iter().map(|s| s.number()).max() else {
+            return Vec::new();
+        };
+
+        let new: Vec<_> = match self.last_sequence {
+            Some(last) if max <= last => {
+                // Nothing is newer than what we have seen, either the
+                // playlist is stale or its numbers are not usable.
+                let new: Vec<_> = segments
+                    .into_iter()
+                    .filter(|s| !self.ids.contains(segment_id(s)))
+                    .collect();
+                if !new.is_empty() {
+                    debug!("[HLS] Media sequence did not advance, deduplicating by uri.");
+                    // Continue counting from the numbers of this playlist.
+                    self.last_sequence = Some(max);
+                }
+                new
+            }
+            Some(last) => segments.into_iter().filter(|s| s.number() > last).collect(),
+            None => segments,
+        };
+
+        if !new.is_empty() {
+            self.last_sequence = Some(self.last_sequence.map_or(max, |last| last.max(max)));
+        }
+        for segment in &new {
+            self.remember(segment_id(segment));
+        }
+
+        new
+    }
+
+    fn remember(&mut self, id: String) {
+        if self.ids.insert(&id) {
+            self.order.push_back(id);
+        }
+        while self.order.len() > ID_WINDOW {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(old);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(first: usize, count: usize) -> String {
+        let mut text = format!(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            first
+        );
+        for n in first..first + count {
+            text.push_str(&format!("#EXTINF:2,\n{}.ts\n", n));
+        }
+        text
+    }
+
+    fn uris(seen: &mut SeenSegments, text: &str) -> Vec<String> {
+        let playlist = MediaPlaylist::try_from(text).unwrap();
+        seen.new_segments(&playlist)
+            .iter()
+            .map(|s| s.uri().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn returns_only_new_segments() {
+        let mut seen = SeenSegments::new();
+        assert_eq!(uris(&mut seen, &playlist(5, 3)), ["5.ts", "6.ts", "7.ts"]);
+        assert_eq!(uris(&mut seen, &playlist(6, 3)), ["8.ts"]);
+        assert!(uris(&mut seen, &playlist(6, 3)).is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_uris_when_numbers_restart() {
+        let mut seen = SeenSegments::new();
+        uris(&mut seen, &playlist(5, 3));
+        // The stream restarted from 0 with segments that were not seen.
+        assert_eq!(uris(&mut seen, &playlist(0, 2)), ["0.ts", "1.ts"]);
+        assert!(uris(&mut seen, &playlist(0, 2)).is_empty());
+    }
+
+    #[test]
+    fn counts_missing_segments() {
+        let mut seen = SeenSegments::new();
+        uris(&mut seen, &playlist(5, 3));
+        let (later, next) = (playlist(11, 3), playlist(8, 3));
+        let later = MediaPlaylist::try_from(later.as_str()).unwrap();
+        assert_eq!(seen.missing(&later), Some((3, Duration::from_secs(6))));
+        let next = MediaPlaylist::try_from(next.as_str()).unwrap();
+        assert_eq!(seen.missing(&next), None);
+    }
+}
@@ -0,0 +1,543 @@
+//! Low-Latency HLS, where the newest segment is published in parts while it
+//! is still being encoded.
+//!
+//! `hls_m3u8` does not understand the LL-HLS tags, so the playlist is scanned
+//! for them here, everything else still comes from the normal parser.
+
+use std::{
+    collections::HashMap,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, FixedOffset};
+use hls_m3u8::{Decryptable, MediaPlaylist, MediaSegment};
+use reqwest::{header::HeaderMap, Client, Url};
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    hls::{
+        decrypt::SegmentKey, program_date_times, segment_url, HlsQueue, ReloadConfig, ReloadTimer,
+        Segment, SegmentFilter, SegmentInfo,
+    },
+    retry::check_status,
+    stats::StatsHandle,
+    Error, ErrorKind,
+};
+
+/// A partial segment (`EXT-X-PART`) or a preload hint
+/// (`EXT-X-PRELOAD-HINT`) for one.
+#[derive(Debug, Clone, PartialEq)]
+struct Part {
+    /// Media sequence number of the segment the part belongs to.
+    sequence: usize,
+    /// Position of the part within its segment.
+    index: usize,
+    uri: String,
+    duration: Duration,
+    range: Option<Range<usize>>,
+}
+
+impl Part {
+    fn position(&self) -> (usize, usize) {
+        (self.sequence, self.index)
+    }
+}
+
+/// The LL-HLS parts of a media playlist.
+#[derive(Debug, Default)]
+struct LowLatencyPlaylist {
+    /// The server supports `_HLS_msn` and `_HLS_part` blocking reloads.
+    can_block_reload: bool,
+    part_target: Option<Duration>,
+    parts: Vec<Part>,
+    /// The next part, which may be requested before it is listed.
+    preload_hint: Option<Part>,
+}
+
+impl LowLatencyPlaylist {
+    fn parse(playlist: &str) -> Self {
+        let mut ll = LowLatencyPlaylist::default();
+        let mut sequence = 0;
+        let mut index = 0;
+        // Parts without an offset continue where the previous one ended.
+        let mut previous_end: Option<(String, usize)> = None;
+
+        for line in playlist.lines().map(str::trim) {
+            if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                sequence = value.trim().parse().unwrap_or(0);
+            } else if let Some(attrs) = line.strip_prefix("#EXT-X-SERVER-CONTROL:") {
+                ll.can_block_reload = attribute(attrs, "CAN-BLOCK-RELOAD") == Some("YES");
+            } else if let Some(attrs) = line.strip_prefix("#EXT-X-PART-INF:") {
+                ll.part_target = attribute(attrs, "PART-TARGET")
+                    .and_then(|t| t.parse().ok())
+                    .and_then(|t| Duration::try_from_secs_f64(t).ok());
+            } else if let Some(attrs) = line.strip_prefix("#EXT-X-PART:") {
+                let Some(uri) = attribute(attrs, "URI") else {
+                    continue;
+                };
+                let range = attribute(attrs, "BYTERANGE").and_then(|r| {
+                    let (len, start): (usize, usize) = match r.split_once('@') {
+                        Some((len, start)) => (len.parse().ok()?, start.parse().ok()?),
+                        None => {
+                            let start = match &previous_end {
+                                Some((prev, end)) if prev == uri => *end,
+                                _ => 0,
+                            };
+                            (r.parse().ok()?, start)
+                        }
+                    };
+                    // A range that overflows is treated as none.
+                    Some(start..start.checked_add(len)?)
+                });
+                previous_end = range.as_ref().map(|r| (uri.to_string(), r.end));
+                ll.parts.push(Part {
+                    sequence,
+                    index,
+                    uri: uri.to_string(),
+                    duration: attribute(attrs, "DURATION")
+                        .and_then(|d| d.parse().ok())
+                        .and_then(|d| Duration::try_from_secs_f64(d).ok())
+                        .unwrap_or_default(),
+                    range,
+                });
+                index += 1;
+            } else if let Some(attrs) = line.strip_prefix("#EXT-X-PRELOAD-HINT:") {
+                let Some(uri) = attribute(attrs, "URI") else {
+                    continue;
+                };
+                if attribute(attrs, "TYPE") != Some("PART") {
+                    continue;
+                }
+                let start: usize = attribute(attrs, "BYTERANGE-START")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(0);
+                let end = attribute(attrs, "BYTERANGE-LENGTH")
+                    .map(|len| len.parse().ok().and_then(|len| start.checked_add(len)));
+                let range = match end {
+                    Some(Some(end)) => Some(start..end),
+                    Some(None) => continue,
+                    None if start == 0 => None,
+                    // The end of the part is not known yet, wait for it to
+                    // be listed instead.
+                    None => continue,
+                };
+                ll.preload_hint = Some(Part {
+                    sequence,
+                    index,
+                    uri: uri.to_string(),
+                    duration: Duration::ZERO,
+                    range,
+                });
+            } else if !line.is_empty() && !line.starts_with('#') {
+                // The uri of a whole segment, the following parts belong to
+                // the next one.
+                sequence += 1;
+                index = 0;
+            }
+        }
+
+        ll
+    }
+
+    /// When `part` starts, from the program date time of its segment, or the
+    /// end of the previous segment if it is not complete yet, and the parts
+    /// before it.
+    fn date_time(
+        &self,
+        m3u8: &MediaPlaylist<'_>,
+        times: &HashMap<usize, DateTime<FixedOffset>>,
+        part: &Part,
+    ) -> Option<DateTime<FixedOffset>> {
+        let start = times.get(&part.sequence).copied().or_else(|| {
+            let (_, previous) = m3u8
+                .segments
+                .iter()
+                .find(|(_, s)| s.number() + 1 == part.sequence)?;
+            let duration = chrono::Duration::from_std(previous.duration.duration()).ok()?;
+            times.get(&previous.number())?.checked_add_signed(duration)
+        })?;
+        let before: Duration = self
+            .parts
+            .iter()
+            .filter(|p| p.sequence == part.sequence && p.index < part.index)
+            .map(|p| p.duration)
+            .sum();
+        start.checked_add_signed(chrono::Duration::from_std(before).ok()?)
+    }
+}
+
+/// Finds the value of `key` in an attribute list like
+/// `URI="part.mp4",DURATION=0.5`, quotes are removed.
+fn attribute<'a>(attrs: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = attrs;
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=')?;
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => after.split_at(after.find(',').unwrap_or(after.len())),
+        };
+        if name.trim() == key {
+            return Some(value);
+        }
+        rest = after.trim_start_matches(',');
+    }
+    None
+}
+
+/// Returns if the playlist uses LL-HLS parts.
+pub(crate) fn is_low_latency(playlist: &str) -> bool {
+    playlist
+        .lines()
+        .any(|l| l.starts_with("#EXT-X-PART-INF:") || l.starts_with("#EXT-X-PART:"))
+}
+
+/// Follows a LL-HLS media playlist part by part.
+pub(crate) struct LowLatencyWatch<'a> {
+    pub(crate) tx: &'a Sender<HlsQueue>,
+    pub(crate) http: &'a Client,
+    pub(crate) headers: &'a HeaderMap,
+    pub(crate) filter: Option<&'a SegmentFilter>,
+    /// The media playlist.
+    pub(crate) url: Url,
+    pub(crate) reload: ReloadConfig,
+    pub(crate) stats: &'a StatsHandle,
+    /// Return when the playlist url expires, so the caller can refresh its
+    /// request.
+    pub(crate) refresh: bool,
+    /// The next part to queue, as media sequence number and part index. It
+    /// is kept by the caller so the watch can continue after a refresh.
+    pub(crate) cursor: &'a mut Option<(usize, usize)>,
+}
+
+impl LowLatencyWatch<'_> {
+    /// Returns the error if the playlist url expired and `refresh` is set,
+    /// otherwise the stream is over.
+    pub(crate) async fn run(self) -> Result<Option<Error>, Error> {
+        // Uris are relative to the media playlist.
+        let base = self.url.clone();
+        let mut can_block_reload = false;
+        let mut timer = ReloadTimer::new(self.reload);
+
+        loop {
+            if timer.over()? {
+                if self.tx.send(HlsQueue::StreamOver).await.is_err() {
+                    return Err(Error::Stopped);
+                };
+                return Ok(None);
+            }
+
+            let mut url = self.url.clone();
+            if let (Some((sequence, part)), true) = (*self.cursor, can_block_reload) {
+                // The server holds the response until this part exists.
+                url.query_pairs_mut()
+                    .append_pair("_HLS_msn", &sequence.to_string())
+                    .append_pair("_HLS_part", &part.to_string());
+            }
+
+            timer.start();
+            let text = match self.fetch(url.clone()).await {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("[HLS] Low latency playlist download failed!\n{}", e);
+                    if self.refresh && e.kind() == ErrorKind::Expired {
+                        return Ok(Some(e));
+                    }
+                    timer.failed(Some(e)).await;
+                    continue;
+                }
+            };
+
+            // LL-HLS playlists use protocol versions newer than the parser
+            // knows about, the version does not matter for what we read.
+            let text: String = text
+                .lines()
+                .filter(|l| !l.starts_with("#EXT-X-VERSION:"))
+                .flat_map(|l| [l, "\n"])
+                .collect();
+
+            let m3u8 = match self.reload.parser().parse(&text) {
+                Ok(p) => p,
+                Err(e) => {
+                    let e = Error::playlist(&url, &text, e.into());
+                    warn!("[HLS] {}", e);
+                    trace!("[HLS]\n{}", &text);
+                    timer.failed(Some(e)).await;
+                    continue;
+                }
+            };
+            timer.loaded(m3u8.target_duration);
+            let ll = LowLatencyPlaylist::parse(&text);
+            can_block_reload = ll.can_block_reload;
+
+            let segments: Vec<&MediaSegment<'_>> = m3u8.segments.iter().map(|(_, s)| s).collect();
+            let complete = segments.last().map(|s| s.number());
+
+            // Start with the segment that is currently being published.
+            let start = *self
+                .cursor
+                .get_or_insert_with(|| (complete.map_or(m3u8.media_sequence, |n| n + 1), 0));
+
+            let times = program_date_times(&m3u8);
+            let mut queue = Vec::new();
+            let mut position = start;
+
+            let first = segments
+                .first()
+                .map(|s| s.number())
+                .into_iter()
+                .chain(ll.parts.first().map(|p| p.sequence))
+                .min();
+            if let Some(first) = first.filter(|first| position.0 < *first) {
+                let missing = first - position.0;
+                warn!(
+                    "[HLS] {} segments fell out of the playlist before they were seen.",
+                    missing
+                );
+                let duration = m3u8.target_duration * missing as u32;
+                queue.push(HlsQueue::Gap {
+                    segments: missing,
+                    duration,
+                });
+                position = (first, 0);
+            }
+
+            loop {
+                let (sequence, index) = position;
+                if let Some(part) = ll.parts.iter().find(|p| p.position() == position) {
+                    let time = ll.date_time(&m3u8, &times, part);
+                    match self.part(&base, &m3u8, part, time) {
+                        Ok(entry) => queue.extend(entry),
+                        Err(error) => {
+                            warn!("[HLS] Skipping part {:?}: {}", position, error);
+                            queue.push(HlsQueue::SegmentFailed {
+                                url: base.clone(),
+                                duration: part.duration,
+                                error,
+                            });
+                        }
+                    }
+                    position = (sequence, index + 1);
+                } else if let Some(segment) = segments.iter().find(|s| s.number() == sequence) {
+                    // The segment is complete, if none of its parts have been
+                    // queued and they are no longer listed, take all of it.
+                    if index == 0 {
+                        let time = times.get(&sequence).copied();
+                        match Segment::new(&base, segment, time) {
+                            Ok(segment) => {
+                                if self.filter.is_none_or(|f| f(&segment.info)) {
+                                    queue.push(HlsQueue::Segment(Box::new(segment)));
+                                }
+                            }
+                            Err(error) => {
+                                warn!("[HLS] Skipping segment {}: {}", sequence, error);
+                                queue.push(HlsQueue::SegmentFailed {
+                                    url: base.clone(),
+                                    duration: segment.duration.duration(),
+                                    error,
+                                });
+                            }
+                        }
+                    } else if let Some(missing) = ll
+                        .part_target
+                        .filter(|_| !ll.parts.iter().any(|p| p.sequence == sequence))
+                        .map(|target| {
+                            segment
+                                .duration
+                                .duration()
+                                .saturating_sub(target * index as u32)
+                        })
+                        .filter(|missing| !missing.is_zero())
+                    {
+                        // Some of the parts were queued, but the rest left the
+                        // playlist before they were seen.
+                        warn!(
+                            "[HLS] The parts of segment {} after part {} fell out of the playlist.",
+                            sequence, index
+                        );
+                        queue.push(HlsQueue::Gap {
+                            segments: 1,
+                            duration: missing,
+                        });
+                    }
+                    position = (sequence + 1, 0);
+                } else {
+                    break;
+                }
+            }
+
+            // Ask for the next part before it is listed, the server answers
+            // as soon as it is ready.
+            if let Some(hint) = ll
+                .preload_hint
+                .as_ref()
+                .filter(|h| h.position() == position)
+            {
+                debug!("[HLS] Following preload hint {}", hint.uri);
+                let time = ll.date_time(&m3u8, &times, hint);
+                queue.extend(self.part(&base, &m3u8, hint, time)?);
+                position = (position.0, position.1 + 1);
+            }
+
+            if position != start {
+                timer.progress();
+            }
+            *self.cursor = Some(position);
+
+            for item in queue {
+                match &item {
+                    HlsQueue::Segment(segment) => self.stats.queued(segment.info.duration),
+                    HlsQueue::SegmentFailed { duration, .. } => self.stats.queued(*duration),
+                    _ => {}
+                }
+                if self.tx.send(item).await.is_err() {
+                    return Err(Error::Stopped);
+                }
+            }
+
+            if m3u8.has_end_list {
+                debug!("List has end, no more segments expected.");
+                if self.tx.send(HlsQueue::StreamOver).await.is_err() {
+                    return Err(Error::Stopped);
+                };
+                return Ok(None);
+            }
+
+            if timer.stalled() {
+                if self.tx.send(HlsQueue::StreamOver).await.is_err() {
+                    return Err(Error::Stopped);
+                };
+                return Ok(None);
+            }
+
+            if !can_block_reload {
+                // Without blocking reloads the playlist is polled once per
+                // part.
+                let wait = ll.part_target.unwrap_or(m3u8.target_duration / 2);
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    async fn fetch(&self, url: Url) -> Result<String, Error> {
+        let reload = Instant::now();
+        let res = self
+            .http
+            .get(url.clone())
+            .headers(self.headers.clone())
+            // A blocking reload may take a few target durations.
+            .timeout(self.reload.timeout * 3)
+            .send()
+            .await
+            .map_err(Error::from)
+            .and_then(check_status);
+        self.stats.request(&url, res.is_ok());
+        let text = res?.text().await?;
+        self.stats.playlist_reloaded(reload.elapsed());
+        Ok(text)
+    }
+
+    /// Makes a queue entry for a part, it uses the key and init section of
+    /// its segment, or of the newest segment if it is not complete yet.
+    fn part(
+        &self,
+        base: &Url,
+        m3u8: &MediaPlaylist<'_>,
+        part: &Part,
+        program_date_time: Option<DateTime<FixedOffset>>,
+    ) -> Result<Option<HlsQueue>, Error> {
+        let template = m3u8
+            .segments
+            .iter()
+            .map(|(_, s)| s)
+            .find(|s| s.number() == part.sequence)
+            .or_else(|| m3u8.segments.iter().map(|(_, s)| s).next_back());
+
+        let info = SegmentInfo {
+            uri: part.uri.clone(),
+            sequence: part.sequence,
+            part: Some(part.index),
+            duration: part.duration,
+            title: None,
+            discontinuity: part.index == 0
+                && template.is_some_and(|t| t.number() == part.sequence && t.has_discontinuity),
+            program_date_time,
+        };
+        let (key, map) = match template {
+            Some(t) => (
+                SegmentKey::from_keys(base, t.keys(), part.sequence)?,
+                Segment::new(base, t, None)?.map,
+            ),
+            None => (None, None),
+        };
+        let segment = Segment {
+            url: segment_url(base, &part.uri)?,
+            info,
+            key,
+            map,
+            range: part.range.clone(),
+        };
+
+        Ok(self
+            .filter
+            .is_none_or(|f| f(&segment.info))
+            .then(|| HlsQueue::Segment(Box::new(segment))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_attributes() {
+        let attrs = r#"DURATION=0.5,URI="part,1.mp4",INDEPENDENT=YES"#;
+        assert_eq!(attribute(attrs, "URI"), Some("part,1.mp4"));
+        assert_eq!(attribute(attrs, "DURATION"), Some("0.5"));
+        assert_eq!(attribute(attrs, "INDEPENDENT"), Some("YES"));
+        assert_eq!(attribute(attrs, "BYTERANGE"), None);
+        assert_eq!(attribute(r#"URI="unterminated"#, "URI"), None);
+    }
+
+    #[test]
+    fn parses_parts() {
+        let ll = LowLatencyPlaylist::parse(
+            "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.5
+#EXT-X-PART-INF:PART-TARGET=0.5
+#EXT-X-MEDIA-SEQUENCE:10
+#EXTINF:4,
+10.mp4
+#EXT-X-PART:DURATION=0.5,URI=\"11.mp4\",BYTERANGE=\"100@0\"
+#EXT-X-PART:DURATION=0.5,URI=\"11.mp4\",BYTERANGE=\"50\"
+#EXT-X-PART:DURATION=0.5,URI=\"11.mp4\",BYTERANGE=\"18446744073709551615@1\"
+#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"11.mp4\",BYTERANGE-START=150,BYTERANGE-LENGTH=20
+",
+        );
+        assert!(ll.can_block_reload);
+        assert_eq!(ll.part_target, Some(Duration::from_millis(500)));
+
+        let parts: Vec<_> = ll
+            .parts
+            .iter()
+            .map(|p| (p.position(), p.range.clone()))
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ((11, 0), Some(0..100)),
+                ((11, 1), Some(100..150)),
+                // A range that overflows is dropped.
+                ((11, 2), None),
+            ]
+        );
+        let hint = ll.preload_hint.unwrap();
+        assert_eq!(hint.position(), (11, 3));
+        assert_eq!(hint.range, Some(150..170));
+    }
+}
@@ -1,37 +1,310 @@
+mod decrypt;
+mod dedup;
+mod low_latency;
 mod named_watch;
+mod reload;
 mod watch;
+mod webvtt;
 
-/// HLS will try and look for new segments 12 times,
+/// How many playlist reloads in a row may fail by default.
 pub const HLS_MAX_RETRIES: usize = 12;
 
-use std::time::Duration;
+/// The watcher waits for the downloader when this many segments are queued.
+pub const HLS_QUEUE_SIZE: usize = 64;
 
-use reqwest::header::HeaderMap;
-use reqwest::{Client, Method, Request, Url};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use hls_m3u8::{
+    tags::VariantStream, types::MediaType, Decryptable, MasterPlaylist, MediaPlaylist, MediaSegment,
+};
+use reqwest::header::{HeaderMap, RANGE};
+use reqwest::{Client, Method, Request, StatusCode, Url};
+
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, FixedOffset};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    Mutex,
+};
 
 use tracing::warn;
 
 use futures_util::StreamExt;
 
-use crate::download_stream::{DownloadStream, Event};
+use crate::config::DownloadConfig;
+use crate::download_stream::{DownloadStream, Event, EventSender};
 use crate::error::Error;
+use crate::rate::RateLimiter;
+use crate::retry::{check_status, RetryPolicy};
+use crate::stats::StatsHandle;
+use crate::variant::{RenditionInfo, RenditionSelector, VariantInfo, VariantSelector};
 
 use watch::HlsWatch;
 
 use named_watch::NamedHlsWatch;
 
-#[derive(Debug, Clone)]
+use decrypt::{KeyCache, SegmentKey};
+
+pub(crate) use reload::{ReloadConfig, ReloadTimer};
+pub use webvtt::SubtitleFormat;
+use webvtt::SubtitleStitcher;
+
+#[derive(Debug)]
 pub enum HlsQueue {
-    Url(Url),
+    Segment(Box<Segment>),
+    /// Segments were removed from the live playlist before we saw them.
+    Gap {
+        segments: usize,
+        duration: Duration,
+    },
+    /// A segment of the playlist can not be downloaded, because its uris are
+    /// not valid. `url` is the playlist it is listed in.
+    SegmentFailed {
+        url: Url,
+        duration: Duration,
+        error: Error,
+    },
+    /// The next segment starts this many seconds into the recording of the
+    /// variant, negative if it starts before it.
+    Offset(f64),
     StreamOver,
+    /// The watcher stopped because of an error.
+    Error(Error),
+}
+
+/// Decides if a segment should be downloaded, segments for which it returns
+/// `false` are left out of the recording.
+pub type SegmentFilter = Box<dyn Fn(&SegmentInfo) -> bool + Send + Sync>;
+
+/// What the playlist says about a media segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInfo {
+    /// The uri as written in the playlist.
+    pub uri: String,
+    /// The media sequence number.
+    pub sequence: usize,
+    /// The index of the part within the segment, for the partial segments
+    /// of Low-Latency HLS.
+    pub part: Option<usize>,
+    pub duration: Duration,
+    /// The title given in `EXTINF`.
+    pub title: Option<String>,
+    /// Set if the segment follows an `EXT-X-DISCONTINUITY` tag.
+    pub discontinuity: bool,
+    /// The wall clock time of the first sample, from `EXT-X-PROGRAM-DATE-TIME`
+    /// or counted forward from an earlier segment in the playlist.
+    pub program_date_time: Option<DateTime<FixedOffset>>,
+}
+
+impl SegmentInfo {
+    fn new(segment: &MediaSegment<'_>, program_date_time: Option<DateTime<FixedOffset>>) -> Self {
+        SegmentInfo {
+            uri: segment.uri().trim().to_string(),
+            sequence: segment.number(),
+            part: None,
+            duration: segment.duration.duration(),
+            title: segment.duration.title().as_ref().map(|t| t.to_string()),
+            discontinuity: segment.has_discontinuity,
+            program_date_time,
+        }
+    }
+}
+
+/// Works out the wall clock time of the segments in a playlist.
+///
+/// Segments without an `EXT-X-PROGRAM-DATE-TIME` tag continue from the last
+/// segment that had one, up to the next discontinuity.
+pub(crate) fn program_date_times(
+    playlist: &MediaPlaylist<'_>,
+) -> HashMap<usize, DateTime<FixedOffset>> {
+    let mut times = HashMap::new();
+    let mut next: Option<DateTime<FixedOffset>> = None;
+
+    for (_, segment) in playlist.segments.iter() {
+        if segment.has_discontinuity {
+            next = None;
+        }
+        // Badly formatted dates are ignored rather than failing the whole
+        // playlist.
+        if let Some(time) = segment
+            .program_date_time
+            .as_ref()
+            .and_then(|pdt| DateTime::parse_from_rfc3339(pdt.date_time.trim()).ok())
+        {
+            next = Some(time);
+        }
+
+        if let Some(time) = next {
+            times.insert(segment.number(), time);
+            next = chrono::Duration::from_std(segment.duration.duration())
+                .ok()
+                .and_then(|d| time.checked_add_signed(d));
+        }
+    }
+
+    times
+}
+
+/// A media segment that should be downloaded.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub url: Url,
+    pub info: SegmentInfo,
+    /// Set if the segment is encrypted with AES-128.
+    pub key: Option<SegmentKey>,
+    /// The media initialization section (`EXT-X-MAP`) the segment depends on.
+    pub map: Option<InitSection>,
+    /// The sub-range of the resource given by `EXT-X-BYTERANGE`.
+    pub range: Option<Range<usize>>,
+}
+
+impl Segment {
+    /// The uris of the segment are resolved against `playlist_url`.
+    pub(crate) fn new(
+        playlist_url: &Url,
+        segment: &MediaSegment<'_>,
+        program_date_time: Option<DateTime<FixedOffset>>,
+    ) -> Result<Self, Error> {
+        let number = segment.number();
+        let map = match &segment.map {
+            Some(map) => Some(InitSection {
+                url: segment_url(playlist_url, map.uri())?,
+                range: map.range().map(|r| r.start().unwrap_or(0)..r.end()),
+                key: SegmentKey::from_keys(playlist_url, map.keys(), number)?,
+            }),
+            None => None,
+        };
+        Ok(Segment {
+            url: segment_url(playlist_url, segment.uri())?,
+            info: SegmentInfo::new(segment, program_date_time),
+            key: SegmentKey::from_keys(playlist_url, segment.keys(), number)?,
+            map,
+            // The playlist parser resolves implicit offsets from the end of the
+            // previous sub-range, so the start is always set here.
+            range: segment.byte_range.map(|r| r.start().unwrap_or(0)..r.end()),
+        })
+    }
+}
+
+/// Identifies a segment in the playlist, segments addressing different
+/// byte ranges of the same resource gets different ids.
+pub(crate) fn segment_id(segment: &MediaSegment<'_>) -> String {
+    let uri = segment.uri().trim();
+    match segment.byte_range {
+        Some(range) => format!("{}@{}-{}", uri, range.start().unwrap_or(0), range.end()),
+        None => uri.to_string(),
+    }
+}
+
+/// A media initialization section, used by fragmented MP4 streams.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitSection {
+    pub url: Url,
+    pub range: Option<Range<usize>>,
+    pub key: Option<SegmentKey>,
+}
+
+/// Which playlist of the selected variant stream to follow.
+#[derive(Debug, Clone)]
+pub(crate) enum Track {
+    /// The variant stream itself.
+    Variant,
+    /// One of the alternative audio renditions of the variant.
+    Audio(RenditionSelector),
+    /// One of the subtitle renditions of the variant.
+    Subtitles(RenditionSelector),
 }
+
+impl Track {
+    /// The selector and type of the rendition, `None` for the variant.
+    pub(crate) fn rendition(&self) -> Option<(&RenditionSelector, MediaType)> {
+        match self {
+            Track::Variant => None,
+            Track::Audio(selector) => Some((selector, MediaType::Audio)),
+            Track::Subtitles(selector) => Some((selector, MediaType::Subtitles)),
+        }
+    }
+}
+
+/// Lists the variant streams of a master playlist, I-frame only variants are
+/// left out.
+pub(crate) fn variants<'a, 'b>(
+    master: &'a MasterPlaylist<'b>,
+) -> Vec<(VariantInfo, &'a VariantStream<'b>)> {
+    master
+        .variant_streams
+        .iter()
+        .filter_map(|variant| match variant {
+            VariantStream::ExtXStreamInf {
+                frame_rate,
+                stream_data,
+                ..
+            } => {
+                let info = VariantInfo {
+                    bandwidth: stream_data.bandwidth(),
+                    resolution: stream_data.resolution().map(Into::into),
+                    frame_rate: frame_rate.map(|r| r.as_f32()),
+                    codecs: stream_data
+                        .codecs()
+                        .map(|c| c.iter().map(|c| c.to_string()).collect())
+                        .unwrap_or_default(),
+                    names: master
+                        .media
+                        .iter()
+                        .filter(|m| variant.is_associated(m))
+                        .map(|m| m.name().to_string())
+                        .collect(),
+                };
+                Some((info, variant))
+            }
+            VariantStream::ExtXIFrame { .. } => None,
+        })
+        .collect()
+}
+
+/// Lists the renditions of `media_type` that belong to `variant`, with their
+/// uris. Renditions without an uri are contained in the variant stream.
+pub(crate) fn renditions(
+    master: &MasterPlaylist<'_>,
+    variant: &VariantStream<'_>,
+    media_type: MediaType,
+) -> Vec<(RenditionInfo, Option<String>)> {
+    master
+        .media
+        .iter()
+        .filter(|m| m.media_type == media_type && variant.is_associated(m))
+        .map(|m| {
+            let info = RenditionInfo {
+                name: m.name().to_string(),
+                language: m.language().as_ref().map(|l| l.to_string()),
+                default: m.is_default,
+            };
+            (info, m.uri().map(|u| u.to_string()))
+        })
+        .collect()
+}
+
+/// Resolves an uri found in a playlist against the url of the playlist, as
+/// described in RFC 3986.
+pub(crate) fn segment_url(playlist_url: &Url, uri: &str) -> Result<Url, Error> {
+    let uri = uri.trim();
+    playlist_url.join(uri).map_err(|error| Error::Uri {
+        uri: uri.to_string(),
+        error,
+    })
+}
+
 pub struct HlsDownloader {
     http: Client,
-    rx: UnboundedReceiver<HlsQueue>,
+    rx: Receiver<HlsQueue>,
     watch: Watcher,
     headers: HeaderMap,
+    subtitles: Option<SubtitleFormat>,
 }
 
 enum Watcher {
@@ -40,16 +313,33 @@ enum Watcher {
 }
 
 impl Watcher {
-    async fn run(self) -> Result<(), Error> {
+    fn configure(&mut self, config: &DownloadConfig, stats: StatsHandle) {
         match self {
-            Watcher::Unnamed(watch) => watch.run().await,
-            Watcher::Named(watch) => watch.run().await,
+            Watcher::Unnamed(watch) => {
+                watch.low_latency = config.low_latency;
+                watch.refresh = config.refresh.clone();
+                watch.reload = ReloadConfig::from(config);
+                watch.stats = stats;
+            }
+            Watcher::Named(watch) => {
+                watch.low_latency = config.low_latency;
+                watch.refresh = config.refresh.clone();
+                watch.reload = ReloadConfig::from(config);
+                watch.stats = stats;
+            }
+        }
+    }
+
+    async fn run(self) {
+        match self {
+            Watcher::Unnamed(watch) => report_error(watch.tx.clone(), watch.run()).await,
+            Watcher::Named(watch) => report_error(watch.tx.clone(), watch.run()).await,
         }
     }
 }
 
 impl HlsDownloader {
-    pub(crate) fn new(request: Request, http: Client, filter: Option<fn(&str) -> bool>) -> Self {
+    pub(crate) fn new(request: Request, http: Client, filter: Option<SegmentFilter>) -> Self {
         let headers = request.headers().clone();
         let (watch, rx) = HlsWatch::new(request, http.clone(), filter);
         Self {
@@ -57,89 +347,280 @@ impl HlsDownloader {
             rx,
             watch: Watcher::Unnamed(watch),
             headers,
+            subtitles: None,
         }
     }
 
-    pub(crate) fn new_named(
+    pub(crate) fn new_variant(
         request: Request,
         http: Client,
-        name: String,
-        filter: Option<fn(&str) -> bool>,
+        selector: VariantSelector,
+        track: Track,
+        filter: Option<SegmentFilter>,
     ) -> Self {
         let headers = request.headers().clone();
-        let (watch, rx) = NamedHlsWatch::new(request, http.clone(), name, filter);
+        let (watch, rx) = NamedHlsWatch::new(request, http.clone(), selector, track, filter);
         Self {
             http,
             rx,
             watch: Watcher::Named(watch),
             headers,
+            subtitles: None,
         }
     }
 
-    pub(crate) fn new_master_first(
-        request: Request,
-        http: Client,
-        filter: Option<fn(&str) -> bool>,
-    ) -> Self {
-        let headers = request.headers().clone();
-        let (watch, rx) = NamedHlsWatch::new_first(request, http.clone(), filter);
-        Self {
-            http,
-            rx,
-            watch: Watcher::Named(watch),
-            headers,
-        }
+    /// Stitches the downloaded WebVTT segments into a single subtitle file.
+    pub(crate) fn stitch_subtitles(mut self, format: SubtitleFormat) -> Self {
+        self.subtitles = Some(format);
+        self
     }
 
-    pub(crate) fn download(self) -> DownloadStream {
-        let rx = self.rx;
-        let watch = self.watch;
+    pub(crate) fn download(self, config: &DownloadConfig) -> DownloadStream {
+        let mut download_stream = forward(self.http, self.headers, self.rx, config, self.subtitles);
 
-        // TODO: Maybe clean this up after closing the function.
-        tokio::task::spawn(watch.run());
+        let mut watch = self.watch;
+        watch.configure(config, download_stream.stats());
+        download_stream.spawn(watch.run());
+        download_stream
+    }
+}
 
-        let (download_stream, event_tx) = DownloadStream::new();
+/// Runs a watcher and queues the error it stops with, so the consumer gets it
+/// as [`Event::Error`].
+pub(crate) async fn report_error<F>(tx: Sender<HlsQueue>, watch: F)
+where
+    F: std::future::Future<Output = Result<(), Error>>,
+{
+    if let Err(error) = watch.await {
+        warn!("Stopped following the playlist: {}", error);
+        // The queue is closed if the download was stopped, then nobody is
+        // left to tell.
+        let _ = tx.send(HlsQueue::Error(error)).await;
+    }
+}
 
-        tokio::task::spawn(bytes_forwarder(self.http, self.headers, rx, event_tx));
+/// Downloads the segments a watcher puts in `rx` and sends them to a new
+/// [`DownloadStream`].
+pub(crate) fn forward(
+    http: Client,
+    headers: HeaderMap,
+    rx: Receiver<HlsQueue>,
+    config: &DownloadConfig,
+    subtitles: Option<SubtitleFormat>,
+) -> DownloadStream {
+    let (mut download_stream, event_tx) = DownloadStream::new(config.buffer, config.overflow);
 
-        download_stream
-    }
+    let stats = download_stream.stats();
+    download_stream.spawn(bytes_forwarder(
+        http,
+        headers,
+        rx,
+        event_tx,
+        stats,
+        config.concurrency,
+        config.retry,
+        config.rate_limit.clone(),
+        config.segment_timeout,
+        subtitles.map(SubtitleStitcher::new),
+    ));
+
+    download_stream
 }
 
+/// A queue entry after its segment has been downloaded.
+enum Downloaded {
+    Segment(Box<Segment>, Result<Bytes, Error>),
+    Gap {
+        segments: usize,
+        duration: Duration,
+    },
+    SegmentFailed {
+        url: Url,
+        duration: Duration,
+        error: Error,
+    },
+    Offset(f64),
+    StreamOver,
+    Error(Error),
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn bytes_forwarder(
     http: Client,
     headers: HeaderMap,
-    mut hls_rx: UnboundedReceiver<HlsQueue>,
-    event_tx: UnboundedSender<Event>,
+    hls_rx: Receiver<HlsQueue>,
+    event_tx: EventSender,
+    stats: StatsHandle,
+    concurrency: usize,
+    retry: RetryPolicy,
+    rate_limit: Option<RateLimiter>,
+    timeout: Duration,
+    mut subtitles: Option<SubtitleStitcher>,
 ) {
-    while let Some(hls) = hls_rx.recv().await {
+    let keys = Arc::new(Mutex::new(KeyCache::default()));
+    // The init section that was last written to the output.
+    let mut current_map: Option<InitSection> = None;
+
+    // Up to `concurrency` segments are downloaded at the same time, but they
+    // are still handed out in the order of the playlist.
+    let queue = futures_util::stream::unfold(hls_rx, |mut rx| async move {
+        rx.recv().await.map(|hls| (hls, rx))
+    });
+    let downloads = queue
+        .map(|hls| {
+            let http = http.clone();
+            let headers = headers.clone();
+            let keys = keys.clone();
+            let rate_limit = rate_limit.clone();
+            let stats = stats.clone();
+            async move {
+                match hls {
+                    HlsQueue::Segment(segment) => {
+                        // Segments are downloaded whole, so the buffer of the
+                        // download stream can count them.
+                        let res = download_with_retry(
+                            retry,
+                            &http,
+                            &headers,
+                            &keys,
+                            &segment.url,
+                            segment.range.as_ref(),
+                            segment.key.as_ref(),
+                            rate_limit.as_ref(),
+                            &stats,
+                            timeout,
+                        )
+                        .await;
+                        Downloaded::Segment(segment, res)
+                    }
+                    HlsQueue::Gap { segments, duration } => Downloaded::Gap { segments, duration },
+                    HlsQueue::SegmentFailed {
+                        url,
+                        duration,
+                        error,
+                    } => Downloaded::SegmentFailed {
+                        url,
+                        duration,
+                        error,
+                    },
+                    HlsQueue::Offset(offset) => Downloaded::Offset(offset),
+                    HlsQueue::StreamOver => Downloaded::StreamOver,
+                    HlsQueue::Error(error) => Downloaded::Error(error),
+                }
+            }
+        })
+        .buffered(concurrency.max(1));
+    let mut downloads = std::pin::pin!(downloads);
+
+    while let Some(downloaded) = downloads.next().await {
         //println!("GOT ELEMENT");
-        match hls {
-            HlsQueue::Url(u) => {
-                // These two statements are not part of the spinner.
-                const TIMEOUT: Duration = Duration::from_secs(10);
-                let req = http
-                    .get(u)
-                    .headers(headers.clone())
-                    .timeout(TIMEOUT)
-                    .build()
-                    .unwrap();
-                if let Err(error) = download_to_file(
-                    http.clone(),
-                    req,
-                    event_tx.clone(),
-                    Some(TIMEOUT),
-                    Some(TIMEOUT),
-                )
-                .await
-                {
-                    if let Err(error) = event_tx.send(Event::Error { error }) {
-                        warn!("Could not send event: {}", error);
-                    };
+        match downloaded {
+            Downloaded::Segment(segment, res) => {
+                let bytes = match res {
+                    Ok(bytes) => bytes,
+                    Err(error) => {
+                        stats.segment_done(segment.info.duration, false);
+                        if let Some(stitcher) = subtitles.as_mut() {
+                            stitcher.gap(segment.info.duration);
+                        }
+                        let event = Event::SegmentFailed {
+                            url: segment.url,
+                            error,
+                        };
+                        if let Err(error) = event_tx.send(event).await {
+                            warn!("Could not send event: {}", error);
+                        };
+                        continue;
+                    }
+                };
+
+                // The init section has to be written before the first segment
+                // and again every time the playlist switches to a new one.
+                let mut init = None;
+                if let Some(map) = segment.map.filter(|m| current_map.as_ref() != Some(m)) {
+                    let res = download_with_retry(
+                        retry,
+                        &http,
+                        &headers,
+                        &keys,
+                        &map.url,
+                        map.range.as_ref(),
+                        map.key.as_ref(),
+                        rate_limit.as_ref(),
+                        &stats,
+                        timeout,
+                    )
+                    .await;
+                    match res {
+                        Ok(bytes) => {
+                            current_map = Some(map);
+                            init = Some(bytes);
+                        }
+                        Err(error) => {
+                            stats.segment_done(segment.info.duration, false);
+                            // The segment can not be decoded without its init
+                            // section so it is skipped.
+                            let event = Event::SegmentFailed {
+                                url: map.url,
+                                error,
+                            };
+                            if let Err(error) = event_tx.send(event).await {
+                                warn!("Could not send event: {}", error);
+                            };
+                            continue;
+                        }
+                    }
+                }
+
+                let bytes = match subtitles.as_mut() {
+                    Some(stitcher) => stitcher.push(&bytes, segment.info.duration),
+                    None => bytes,
+                };
+
+                stats.segment_done(segment.info.duration, true);
+                if let Err(error) = event_tx.send_segment(segment.info, init, bytes).await {
+                    warn!("Could not send event: {}", error);
+                };
+            }
+            Downloaded::Gap { segments, duration } => {
+                if let Some(stitcher) = subtitles.as_mut() {
+                    stitcher.gap(duration);
+                }
+                let event = Event::Gap {
+                    missing_segments: segments,
+                    missing_duration: duration,
+                };
+                if let Err(error) = event_tx.send(event).await {
+                    warn!("Could not send event: {}", error);
+                };
+            }
+            Downloaded::SegmentFailed {
+                url,
+                duration,
+                error,
+            } => {
+                stats.segment_done(duration, false);
+                if let Some(stitcher) = subtitles.as_mut() {
+                    stitcher.gap(duration);
+                }
+                let event = Event::SegmentFailed { url, error };
+                if let Err(error) = event_tx.send(event).await {
+                    warn!("Could not send event: {}", error);
+                };
+            }
+            Downloaded::Offset(offset) => {
+                if let Some(stitcher) = subtitles.as_mut() {
+                    stitcher.start_at(offset);
                 }
             }
-            HlsQueue::StreamOver => {
-                if let Err(error) = event_tx.send(Event::End) {
+            Downloaded::StreamOver => {
+                if let Err(error) = event_tx.send(Event::End).await {
+                    warn!("Could not send event: {}", error);
+                };
+                break;
+            }
+            Downloaded::Error(error) => {
+                if let Err(error) = event_tx.send(Event::Error { error }).await {
                     warn!("Could not send event: {}", error);
                 };
                 break;
@@ -148,39 +629,180 @@ async fn bytes_forwarder(
     }
 }
 
-/// Timeout is the total duration the response may take set to none to make it unlimited.
-/// Download timeout is the timeout between two chunks of a streaming response.
-pub(crate) async fn download_to_file(
-    client: Client,
-    mut request: Request,
-    event_tx: UnboundedSender<Event>,
-    timeout: Option<Duration>,
-    download_timeout: Option<Duration>,
-) -> Result<(), Error> {
-    const TIMEOUT_MAX: Duration = Duration::MAX;
-
-    *request.timeout_mut() = timeout;
-    let mut stream = client.execute(request).await?.bytes_stream();
-    while let Ok(Some(item)) =
-        tokio::time::timeout(download_timeout.unwrap_or(TIMEOUT_MAX), stream.next()).await
-    {
-        match item {
+/// Calls [`download_resource`] until it succeeds or `retry` gives up.
+#[allow(clippy::too_many_arguments)]
+async fn download_with_retry(
+    retry: RetryPolicy,
+    http: &Client,
+    headers: &HeaderMap,
+    keys: &Mutex<KeyCache>,
+    url: &Url,
+    range: Option<&Range<usize>>,
+    key: Option<&SegmentKey>,
+    rate_limit: Option<&RateLimiter>,
+    stats: &StatsHandle,
+    timeout: Duration,
+) -> Result<Bytes, Error> {
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let res =
+            download_resource(http, headers, keys, url, range, key, rate_limit, timeout).await;
+        // Only failed requests count against the host, not failed decryption.
+        let failed_request = matches!(
+            res,
+            Err(Error::Status { .. } | Error::Reqwest(_) | Error::Timeout(_))
+        );
+        stats.request(url, !failed_request);
+        match res {
             Ok(bytes) => {
-                if let Err(error) = event_tx.send(Event::Bytes { bytes }) {
-                    warn!("Could not send event: {}", error);
-                };
+                stats.downloaded(started.elapsed());
+                stats.received(bytes.len());
+                return Ok(bytes);
             }
-            Err(error) => {
-                if let Err(error) = event_tx.send(Event::Error {
-                    error: error.into(),
-                }) {
-                    warn!("Could not send event: {}", error);
-                };
+            Err(error) => match retry.delay(attempt, &error) {
+                Some(delay) => {
+                    warn!(
+                        "[HLS] Downloading {} failed: {}, retrying in {:?}.",
+                        url, error, delay
+                    );
+                    stats.retry();
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(error),
+            },
+        }
+    }
+}
+
+/// Downloads a whole segment or init section into memory.
+///
+/// Only the bytes in `range` are requested if it is given, and the result is
+/// decrypted if there is a key, the key is fetched with the headers of the
+/// original request.
+///
+/// With a `rate_limit` the body may take longer than `timeout`, only the
+/// response headers and every chunk of the body have to arrive within it.
+#[allow(clippy::too_many_arguments)]
+async fn download_resource(
+    http: &Client,
+    headers: &HeaderMap,
+    keys: &Mutex<KeyCache>,
+    url: &Url,
+    range: Option<&Range<usize>>,
+    key: Option<&SegmentKey>,
+    rate_limit: Option<&RateLimiter>,
+    timeout: Duration,
+) -> Result<Bytes, Error> {
+    if range.is_some_and(|r| r.is_empty()) {
+        // An empty byte range has nothing to download.
+        return Ok(Bytes::new());
+    }
+    let mut builder = http.get(url.clone()).headers(headers.clone());
+    if rate_limit.is_none() {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(range) = range {
+        // The end of a http range is inclusive.
+        builder = builder.header(RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+    }
+
+    let res = match tokio::time::timeout(timeout, http.execute(builder.build()?)).await {
+        Ok(res) => check_status(res?)?,
+        Err(_) => return Err(Error::Timeout(url.clone())),
+    };
+    let partial = res.status() == StatusCode::PARTIAL_CONTENT;
+
+    let mut data = BytesMut::new();
+    let mut stream = res.bytes_stream();
+    loop {
+        let item = match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(item)) => item?,
+            Ok(None) => break,
+            // A stalled body would otherwise end up as a truncated segment.
+            Err(_) => return Err(Error::Timeout(url.clone())),
+        };
+        if let Some(rate_limit) = rate_limit {
+            rate_limit.acquire(item.len()).await;
+        }
+        data.extend_from_slice(&item);
+    }
+    let mut data = data.freeze();
+
+    if let Some(range) = range.filter(|_| !partial) {
+        // The server ignored the range and sent the whole resource.
+        data = data.slice(range.start.min(data.len())..range.end.min(data.len()));
+    }
+
+    match key {
+        Some(key) => {
+            let cipher_key = keys
+                .lock()
+                .await
+                .get(http, headers, &key.url, timeout)
+                .await?;
+            decrypt::decrypt(&cipher_key, &key.iv, &data)
+        }
+        None => Ok(data),
+    }
+}
+
+/// Fetches the playlist and guesses which container the segments use.
+///
+/// If the request points to a master playlist the variant picked by
+/// `selector` is used, or the first one without a selector. Streams using `EXT-X-MAP` are fragmented MP4 and gives `"mp4"`,
+/// everything else is assumed to be MPEG-TS and gives `"ts"`. Segments may
+/// be up to `excess_duration` longer than the target duration.
+pub(crate) async fn probe_extension(
+    http: Client,
+    request: Request,
+    selector: Option<&VariantSelector>,
+    excess_duration: Duration,
+) -> Result<&'static str, Error> {
+    let headers = request.headers().clone();
+    let res = http.execute(request).await?.error_for_status()?;
+    let url = res.url().clone();
+    let mut playlist = res.text().await?;
+
+    if let Ok(master) = MasterPlaylist::try_from(playlist.as_str()) {
+        let variants = variants(&master);
+        let index = match selector {
+            Some(selector) => {
+                let infos: Vec<_> = variants.iter().map(|(info, _)| info.clone()).collect();
+                selector.select(&infos)
             }
+            None => (!variants.is_empty()).then_some(0),
+        };
+        let variant = index.and_then(|i| match variants[i].1 {
+            VariantStream::ExtXStreamInf { uri, .. } => Some(uri),
+            VariantStream::ExtXIFrame { .. } => None,
+        });
+        if let Some(uri) = variant {
+            playlist = http
+                .get(segment_url(&url, uri)?)
+                .headers(headers)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
         }
     }
 
-    Ok(())
+    let mut parser = MediaPlaylist::builder();
+    parser.allowable_excess_duration(excess_duration);
+    let media = parser.parse(&playlist)?;
+
+    let fragmented = media.segments.iter().any(|(_, segment)| {
+        segment.map.is_some() || {
+            let uri = segment.uri().trim();
+            let path = uri.split('?').next().unwrap_or(uri);
+            path.ends_with(".m4s") || path.ends_with(".mp4")
+        }
+    });
+
+    Ok(if fragmented { "mp4" } else { "ts" })
 }
 
 pub fn clone_request(request: &Request, timeout: Duration) -> Request {
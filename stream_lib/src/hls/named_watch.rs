@@ -1,114 +1,115 @@
-use std::time::Duration;
+use std::time::Instant;
 
 use hls_m3u8::{tags::VariantStream, MasterPlaylist, MediaPlaylist};
-use patricia_tree::PatriciaSet;
 use reqwest::{Client, Request, Url};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tracing::{debug, trace, warn};
 
 use crate::{
-    hls::{clone_request, HLS_MAX_RETRIES},
-    Error,
+    hls::{clone_request, HLS_QUEUE_SIZE},
+    refresh::RequestRefresh,
+    retry::check_status,
+    stats::StatsHandle,
+    Error, VariantSelector,
 };
 
-use super::HlsQueue;
+use super::{
+    dedup::SeenSegments,
+    low_latency::{is_low_latency, LowLatencyWatch},
+    program_date_times, renditions, segment_url, variants,
+    watch::queue_playlist,
+    HlsQueue, ReloadConfig, ReloadTimer, SegmentFilter, Track,
+};
 
 pub struct NamedHlsWatch {
-    tx: UnboundedSender<HlsQueue>,
+    pub(crate) tx: Sender<HlsQueue>,
     request: Request,
     http: Client,
-    links: PatriciaSet,
-    master_url: Url,
-    timeout: Duration,
-    name: Option<String>,
-    filter: Option<fn(&str) -> bool>,
+    seen: SeenSegments,
+    pub(crate) reload: ReloadConfig,
+    selector: VariantSelector,
+    track: Track,
+    filter: Option<SegmentFilter>,
+    /// Follow LL-HLS playlists part by part.
+    pub(crate) low_latency: bool,
+    /// Replaces the request when the master playlist url expires.
+    pub(crate) refresh: Option<RequestRefresh>,
+    /// Where the low latency watch continues after a refresh.
+    low_latency_cursor: Option<(usize, usize)>,
+    /// Whether the start of a subtitle rendition was lined up with the
+    /// variant.
+    offset_sent: bool,
+    pub(crate) stats: StatsHandle,
 }
 
 impl NamedHlsWatch {
     pub(crate) fn new(
         request: Request,
         http: Client,
-        name: String,
-        filter: Option<fn(&str) -> bool>,
-    ) -> (Self, UnboundedReceiver<HlsQueue>) {
-        let (tx, rx) = unbounded_channel();
-        let master_url = request
-            .url()
-            .clone()
-            .join(".")
-            .expect("Could not join url with '.'.");
+        selector: VariantSelector,
+        track: Track,
+        filter: Option<SegmentFilter>,
+    ) -> (Self, Receiver<HlsQueue>) {
+        let (tx, rx) = channel(HLS_QUEUE_SIZE);
         (
             Self {
                 tx,
                 request,
                 http,
-                links: PatriciaSet::new(),
-                timeout: Duration::from_secs(10),
-                master_url,
-                name: Some(name),
-                filter,
-            },
-            rx,
-        )
-    }
-
-    pub(crate) fn new_first(
-        request: Request,
-        http: Client,
-        filter: Option<fn(&str) -> bool>,
-    ) -> (Self, UnboundedReceiver<HlsQueue>) {
-        let (tx, rx) = unbounded_channel();
-        let master_url = request
-            .url()
-            .clone()
-            .join(".")
-            .expect("Could not join url with '.'.");
-        (
-            Self {
-                tx,
-                request,
-                http,
-                links: PatriciaSet::new(),
-                timeout: Duration::from_secs(10),
-                master_url,
-                name: None,
+                seen: SeenSegments::new(),
+                reload: ReloadConfig::default(),
+                selector,
+                track,
                 filter,
+                low_latency: false,
+                refresh: None,
+                low_latency_cursor: None,
+                offset_sent: false,
+                stats: StatsHandle::new(),
             },
             rx,
         )
     }
 
     pub async fn run(mut self) -> Result<(), Error> {
-        let mut counter = 0;
+        let mut timer = ReloadTimer::new(self.reload);
 
         loop {
-            if counter > HLS_MAX_RETRIES {
-                // There have either been errors or no new segments
-                // for `HLS_MAX_RETRIES` times the segment duration given
-                // in the m3u8 playlist file.
-                if self.tx.send(HlsQueue::StreamOver).is_err() {
-                    return Err(Error::TIO(std::io::Error::last_os_error()));
+            if timer.over()? {
+                if self.tx.send(HlsQueue::StreamOver).await.is_err() {
+                    return Err(Error::Stopped);
                 };
                 break;
             }
 
             // Use the same headers as the original request
-            let req = clone_request(&self.request, self.timeout);
+            let req = clone_request(&self.request, self.reload.timeout);
+            let url = req.url().clone();
+            timer.start();
+            let reload = Instant::now();
 
             let master_res = match self.http.execute(req).await {
+                Ok(r) => check_status(r),
+                Err(e) => Err(e.into()),
+            };
+            self.stats.request(&url, master_res.is_ok());
+            let master_res = match master_res {
                 Ok(r) => r,
                 Err(e) => {
                     warn!("[HLS] Master playlist download failed!\n{}", e);
-                    counter += 1;
+                    self.refresh_expired(&e).await;
+                    timer.failed(Some(e)).await;
                     continue;
                 }
             };
 
+            // The variant is relative to the master playlist after redirects.
+            let master_url = master_res.url().clone();
             let master_string = match master_res.text().await {
                 Ok(t) => t,
                 Err(e) => {
                     warn!("[HLS] Playlist text failed!\n{}", e);
-                    counter += 1;
+                    timer.failed(Some(e.into())).await;
                     continue;
                 }
             };
@@ -116,159 +117,247 @@ impl NamedHlsWatch {
             let master_playlist = match MasterPlaylist::try_from(master_string.as_str()) {
                 Ok(mp) => mp,
                 Err(e) => {
-                    warn!("[HLS] Master playlist parsing failed: {}", e);
-                    counter += 1;
+                    let e = Error::playlist(&master_url, &master_string, e.into());
+                    warn!("[HLS] {}", e);
+                    timer.failed(Some(e)).await;
                     continue;
                 }
             };
 
-            let ext_media = if let Some(name) = &self.name {
-                match master_playlist.media.iter().find(|e| e.name() == name) {
-                    Some(em) => Some(em),
-                    None => {
-                        counter += 1;
-                        continue;
-                    }
+            let variants = variants(&master_playlist);
+            let infos: Vec<_> = variants.iter().map(|(info, _)| info.clone()).collect();
+            let variant = match self.selector.select(&infos) {
+                Some(i) => variants[i].1,
+                None => {
+                    warn!("[HLS] No variant matches {:?}", self.selector);
+                    timer.failed(None).await;
+                    continue;
                 }
-            } else {
-                None
             };
 
-            let media = if let Some(ext_media) = ext_media {
-                match master_playlist
-                    .variant_streams
-                    .iter()
-                    .find(|e| e.is_associated(ext_media))
-                {
-                    Some(m) => m,
-                    None => {
-                        counter += 1;
-                        continue;
-                    }
-                }
-            } else {
-                match master_playlist.variant_streams.first() {
-                    Some(m) => m,
-                    None => {
-                        counter += 1;
-                        continue;
+            let variant_uri = match variant {
+                VariantStream::ExtXStreamInf { uri, .. } => uri.to_string(),
+                VariantStream::ExtXIFrame { uri, .. } => uri.to_string(),
+            };
+            let uri = match self.track.rendition() {
+                None => variant_uri.clone(),
+                Some((selector, media_type)) => {
+                    let renditions = renditions(&master_playlist, variant, media_type);
+                    let infos: Vec<_> = renditions.iter().map(|(info, _)| info.clone()).collect();
+                    match selector.select(&infos).map(|i| renditions[i].1.clone()) {
+                        Some(Some(uri)) => uri,
+                        Some(None) => {
+                            debug!(
+                                "[HLS] The {:?} rendition is part of the variant.",
+                                media_type
+                            );
+                            if self.tx.send(HlsQueue::StreamOver).await.is_err() {
+                                return Err(Error::Stopped);
+                            };
+                            return Ok(());
+                        }
+                        None => {
+                            warn!("[HLS] No {:?} rendition matches {:?}", media_type, selector);
+                            timer.failed(None).await;
+                            continue;
+                        }
                     }
                 }
             };
 
-            let uri = match media {
-                VariantStream::ExtXIFrame { uri: u, .. } => u,
-                VariantStream::ExtXStreamInf { uri: u, .. } => u,
-            };
-
-            if let Ok(u) = Url::parse(uri) {
-                self.master_url = u.join(".").expect("Could not join with '.'");
-            }
-
-            let uri_formatted = if let Ok(u) = Url::parse(uri) {
-                u
-            } else {
-                Url::parse(&format!("{}{}", self.master_url.as_str(), &uri))
-                    .expect("The m3u8 does not currently work with stream_lib, please report the issue on the github repo, with an example of the playlistfile.")
-            };
+            let uri_formatted = segment_url(&master_url, &uri)?;
 
             let mp_hls = match self
                 .http
                 .get(uri_formatted.as_ref())
                 .headers(self.request.headers().clone())
-                .timeout(self.timeout)
+                .timeout(self.reload.timeout)
                 .build()
             {
                 Ok(p) => p,
                 Err(e) => {
                     debug!("[HLS] URI!\n{}", e);
-                    trace!("[HLS]\n{}", media);
-                    counter += 1;
+                    trace!("[HLS]\n{}", uri);
+                    timer.failed(Some(e.into())).await;
                     continue;
                 }
             };
 
+            let url = mp_hls.url().clone();
             let res = match self.http.execute(mp_hls).await {
+                Ok(r) => check_status(r),
+                Err(e) => Err(e.into()),
+            };
+            self.stats.request(&url, res.is_ok());
+            let res = match res {
                 Ok(r) => r,
                 Err(e) => {
                     warn!("[HLS] Minor playlist download failed!\n{}", e);
-                    counter += 1;
+                    // The media playlist is signed by the master playlist,
+                    // so a new master playlist is needed.
+                    self.refresh_expired(&e).await;
+                    timer.failed(Some(e)).await;
                     continue;
                 }
             };
 
+            let playlist_url = res.url().clone();
             let m3u8_string = match res.text().await {
                 Ok(t) => t,
                 Err(e) => {
                     warn!("[HLS] Playlist text failed!\n{}", e);
-                    counter += 1;
+                    timer.failed(Some(e.into())).await;
                     continue;
                 }
             };
+            self.stats.playlist_reloaded(reload.elapsed());
 
-            let mut m3u8_parser = MediaPlaylist::builder();
+            if self.low_latency && is_low_latency(&m3u8_string) {
+                debug!("[HLS] Following the playlist in low latency mode.");
+                let watch = LowLatencyWatch {
+                    tx: &self.tx,
+                    http: &self.http,
+                    headers: self.request.headers(),
+                    filter: self.filter.as_ref(),
+                    url: playlist_url,
+                    reload: self.reload,
+                    stats: &self.stats,
+                    refresh: self.refresh.is_some(),
+                    cursor: &mut self.low_latency_cursor,
+                };
+                match watch.run().await? {
+                    // The playlist is loaded again with the new request.
+                    Some(e) if self.refresh_expired(&e).await => continue,
+                    Some(e) => return Err(e),
+                    None => return Ok(()),
+                }
+            }
 
             // Allow excess segment duration because a lot of video sites have
             // not very high quality m3u8 playlists, where the video segments,
             // may be longer than what the file specifies as max.
-            m3u8_parser.allowable_excess_duration(Duration::from_secs(10));
-
-            let m3u8 = match m3u8_parser.parse(&m3u8_string) {
+            let m3u8 = match self.reload.parser().parse(&m3u8_string) {
                 Ok(p) => p,
                 Err(e) => {
-                    warn!("[HLS] Parsing failed!\n{}", e);
+                    let e = Error::playlist(&playlist_url, &m3u8_string, e.into());
+                    warn!("[HLS] {}", e);
                     trace!("[HLS]\n{}", &m3u8_string);
-                    counter += 1;
+                    timer.failed(Some(e)).await;
                     continue;
                 }
             };
+            timer.loaded(m3u8.target_duration);
 
-            // Get the target duration of a segment
-            let target_duration = m3u8.target_duration;
-
-            // Makes a iterator with the url parts from the playlist
-            let m3u8_iterator = m3u8
-                .segments
-                .iter()
-                .map(|(_, e)| String::from(e.uri().trim()));
-
-            for e in m3u8_iterator {
-                trace!("[HLS] Tries to inserts: {}", e);
-                // Check if we have the segment in our set already
-                if self.links.insert(&e) {
-                    // Reset the counter as we got a new segment.
-                    counter = 0;
-
-                    // Construct a url from the master and the segment.
-                    let url_formatted = if let Ok(u) = Url::parse(&e) {
-                        u
-                    } else {
-                        Url::parse(&format!("{}{}", self.master_url.as_str(), &e))
-                        .expect("The m3u8 does not currently work with stream_lib, please report the issue on the github repo, with an example of the playlistfile.")
-                    };
-
-                    // Check that the filter runs.
-                    if self.filter.map_or(true, |f| f(&e)) {
-                        debug!("[HLS] Adds {}!", url_formatted);
-                        // Add the segment to the queue.
-                        if self.tx.send(HlsQueue::Url(url_formatted)).is_err() {
-                            return Err(Error::TIO(std::io::Error::last_os_error()));
-                        };
-                    }
+            if matches!(self.track, Track::Subtitles(_)) && !self.offset_sent {
+                // The cues are timed from where a recording of the variant
+                // started at the same time begins.
+                let offset = self
+                    .variant_offset(segment_url(&master_url, &variant_uri)?, &m3u8)
+                    .await;
+                if self.tx.send(HlsQueue::Offset(offset)).await.is_err() {
+                    return Err(Error::Stopped);
                 }
+                self.offset_sent = true;
             }
 
-            if m3u8.has_end_list {
-                tracing::debug!("List has end, no more segments expected.");
+            let over = queue_playlist(
+                &self.tx,
+                &mut self.seen,
+                &mut timer,
+                &m3u8,
+                &playlist_url,
+                self.filter.as_ref(),
+                &self.stats,
+            )
+            .await?;
+            if over {
                 break;
             }
-
-            debug!("[HLS] Sleeps for {:#?}", target_duration);
-            // Sleeps for the target duration.
-            tokio::time::sleep(target_duration).await;
-            counter += 1;
         }
 
         Ok(())
     }
+
+    /// Loads the media playlist of the variant at `url` and returns how many
+    /// seconds after its first segment `playlist` starts, zero if it can not
+    /// be loaded.
+    async fn variant_offset(&self, url: Url, playlist: &MediaPlaylist<'_>) -> f64 {
+        let res = match self
+            .http
+            .get(url.clone())
+            .headers(self.request.headers().clone())
+            .timeout(self.reload.timeout)
+            .send()
+            .await
+        {
+            Ok(r) => check_status(r),
+            Err(e) => Err(e.into()),
+        };
+        self.stats.request(&url, res.is_ok());
+        let text = match res {
+            Ok(r) => r.text().await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        let variant = match text {
+            Ok(text) => text,
+            Err(e) => {
+                warn!(
+                    "[HLS] Could not line up the subtitles with the variant!\n{}",
+                    e
+                );
+                return 0.0;
+            }
+        };
+        let offset = match self.reload.parser().parse(&variant) {
+            Ok(parsed) => start_offset(&parsed, playlist),
+            Err(e) => {
+                let e = Error::playlist(&url, &variant, e.into());
+                warn!(
+                    "[HLS] Could not line up the subtitles with the variant!\n{}",
+                    e
+                );
+                0.0
+            }
+        };
+        offset
+    }
+
+    /// Asks for a new master playlist request if `error` says the current
+    /// one expired, returns `true` if it was replaced.
+    async fn refresh_expired(&mut self, error: &Error) -> bool {
+        match &self.refresh {
+            Some(refresh) => refresh.refresh_expired(error, &mut self.request).await,
+            None => false,
+        }
+    }
+}
+
+/// Seconds from the first segment of `main` to the first segment of `other`,
+/// by their program date times if both have one, otherwise by adding up the
+/// segments between their media sequence numbers.
+fn start_offset(main: &MediaPlaylist<'_>, other: &MediaPlaylist<'_>) -> f64 {
+    let first = |p: &MediaPlaylist<'_>| p.segments.iter().map(|(_, s)| s.number()).min();
+    let (Some(main_first), Some(other_first)) = (first(main), first(other)) else {
+        return 0.0;
+    };
+
+    let main_time = program_date_times(main).remove(&main_first);
+    let other_time = program_date_times(other).remove(&other_first);
+    if let (Some(main_time), Some(other_time)) = (main_time, other_time) {
+        return (other_time - main_time).num_milliseconds() as f64 / 1000.0;
+    }
+
+    let between = |p: &MediaPlaylist<'_>, from: usize, to: usize| -> f64 {
+        p.segments
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| (from..to).contains(&s.number()))
+            .map(|s| s.duration.duration().as_secs_f64())
+            .sum()
+    };
+    if other_first >= main_first {
+        between(main, main_first, other_first)
+    } else {
+        -between(other, other_first, main_first)
+    }
 }
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use hls_m3u8::{builder::MediaPlaylistBuilder, MediaPlaylist};
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+use crate::{config::DownloadConfig, Error};
+
+/// How long to wait after a failed reload if no playlist was loaded yet.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+/// A stream is never over before it went this many target durations
+/// without new segments.
+const END_TARGETS: u32 = 3;
+
+/// How the watchers reload playlists and manifests, from the
+/// [`DownloadConfig`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReloadConfig {
+    pub(crate) timeout: Duration,
+    /// Reloads in a row that may fail.
+    pub(crate) retries: usize,
+    /// How long there may be no new segments before the stream is over.
+    pub(crate) end_timeout: Duration,
+    pub(crate) excess_duration: Duration,
+    pub(crate) interval: Option<Duration>,
+}
+
+impl ReloadConfig {
+    /// A parser for media playlists that accepts segments which are longer
+    /// than the target duration.
+    pub(crate) fn parser<'a>(&self) -> MediaPlaylistBuilder<'a> {
+        let mut parser = MediaPlaylist::builder();
+        parser.allowable_excess_duration(self.excess_duration);
+        parser
+    }
+}
+
+impl From<&DownloadConfig> for ReloadConfig {
+    fn from(config: &DownloadConfig) -> Self {
+        ReloadConfig {
+            timeout: config.playlist_timeout,
+            retries: config.playlist_retries,
+            end_timeout: config.end_timeout,
+            excess_duration: config.allowable_excess_duration,
+            interval: config.reload_interval,
+        }
+    }
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        ReloadConfig::from(&DownloadConfig::default())
+    }
+}
+
+/// Decides when a live playlist is reloaded and when it is over.
+///
+/// Reloads follow RFC 8216 section 6.3.4: the next reload starts one target
+/// duration after the last one started if it brought new segments, and half
+/// a target duration after it if not. Failed reloads count against
+/// `retries`, while a playlist that loads fine but stays without new
+/// segments ends after `end_timeout`, or three target durations if that is
+/// longer.
+pub(crate) struct ReloadTimer {
+    config: ReloadConfig,
+    /// When the current reload started.
+    started: Instant,
+    /// When the last new segment was seen.
+    last_new: Instant,
+    /// If the current reload brought new segments.
+    changed: bool,
+    /// The target duration of the last playlist that was loaded.
+    target: Option<Duration>,
+    failures: usize,
+    /// Why the last reload failed, the download fails with it once the
+    /// retries run out.
+    last_error: Option<Error>,
+}
+
+impl ReloadTimer {
+    pub(crate) fn new(config: ReloadConfig) -> Self {
+        let now = Instant::now();
+        ReloadTimer {
+            config,
+            started: now,
+            last_new: now,
+            changed: false,
+            target: None,
+            failures: 0,
+            last_error: None,
+        }
+    }
+
+    /// Returns `true` if the stream is over, or the error of the last reload
+    /// if too many of them failed in a row.
+    pub(crate) fn over(&mut self) -> Result<bool, Error> {
+        if self.failures > self.config.retries {
+            return match self.last_error.take() {
+                Some(error) => Err(error),
+                None => Ok(true),
+            };
+        }
+        Ok(false)
+    }
+
+    /// Returns `true` if the reload that was just loaded brought nothing new
+    /// and there have been no new segments for too long.
+    ///
+    /// Only a playlist that can be loaded tells that nothing new comes, so it
+    /// is asked after a successful reload and never after a failed one.
+    pub(crate) fn stalled(&self) -> bool {
+        if self.changed {
+            return false;
+        }
+        let end = self.target.map_or(self.config.end_timeout, |target| {
+            self.config.end_timeout.max(target * END_TARGETS)
+        });
+        if self.last_new.elapsed() > end {
+            debug!("No new segments for {:?}, the stream is over.", end);
+            return true;
+        }
+        false
+    }
+
+    /// Marks the start of a reload.
+    pub(crate) fn start(&mut self) {
+        self.started = Instant::now();
+        self.changed = false;
+    }
+
+    /// The reload failed because of `error`, waits before the next one.
+    pub(crate) async fn failed(&mut self, error: Option<Error>) {
+        self.failures += 1;
+        self.last_error = error;
+        let wait = self.target.map_or(FIRST_RETRY, |t| t / 2);
+        trace!("Retries the reload in {:?}", wait);
+        tokio::time::sleep_until(self.started + wait).await;
+    }
+
+    /// The playlist was loaded, `target` is its target duration.
+    pub(crate) fn loaded(&mut self, target: Duration) {
+        self.failures = 0;
+        self.last_error = None;
+        self.target = Some(target);
+    }
+
+    /// The reload brought a new segment.
+    pub(crate) fn progress(&mut self) {
+        self.changed = true;
+        self.last_new = Instant::now();
+    }
+
+    /// Waits until a HLS playlist with `target` duration should be reloaded.
+    pub(crate) async fn wait(&mut self, target: Duration) {
+        let wait = if self.changed { target } else { target / 2 };
+        self.sleep(wait).await;
+    }
+
+    /// Waits until `wait` after the start of the reload, or the reload
+    /// interval if it is set.
+    pub(crate) async fn sleep(&mut self, wait: Duration) {
+        let wait = self.config.interval.unwrap_or(wait);
+        trace!("Reloads in {:?}", wait);
+        tokio::time::sleep_until(self.started + wait).await;
+    }
+}
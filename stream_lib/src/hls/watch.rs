@@ -1,150 +1,249 @@
-use std::time::Duration;
+use std::time::Instant;
 
 use hls_m3u8::MediaPlaylist;
-use patricia_tree::PatriciaSet;
-
 use reqwest::{Client, Request, Url};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tracing::{debug, trace, warn};
 
 use crate::{
-    hls::{clone_request, HlsQueue, HLS_MAX_RETRIES},
+    hls::{
+        clone_request,
+        dedup::SeenSegments,
+        low_latency::{is_low_latency, LowLatencyWatch},
+        program_date_times, segment_id, HlsQueue, ReloadConfig, ReloadTimer, Segment,
+        SegmentFilter, HLS_QUEUE_SIZE,
+    },
+    refresh::RequestRefresh,
+    retry::check_status,
+    stats::StatsHandle,
     Error,
 };
 
 pub struct HlsWatch {
-    tx: UnboundedSender<HlsQueue>,
+    pub(crate) tx: Sender<HlsQueue>,
     request: Request,
     http: Client,
-    links: PatriciaSet,
-    master_url: Url,
-    timeout: Duration,
-    fail_counter: usize,
-    filter: Option<fn(&str) -> bool>,
+    seen: SeenSegments,
+    pub(crate) reload: ReloadConfig,
+    filter: Option<SegmentFilter>,
+    /// Follow LL-HLS playlists part by part.
+    pub(crate) low_latency: bool,
+    /// Replaces the request when the playlist url expires.
+    pub(crate) refresh: Option<RequestRefresh>,
+    /// Where the low latency watch continues after a refresh.
+    low_latency_cursor: Option<(usize, usize)>,
+    pub(crate) stats: StatsHandle,
 }
 
 impl HlsWatch {
-    /// Filter will filter any url that returns `false`, if `None` it will not filter anything.
-    /// For example if you want filter preloading segments use: `|e| !(e.contains("preloading"))`.
+    /// Filter will filter any segment that returns `false`, if `None` it will not filter anything.
+    /// For example if you want filter preloading segments use:
+    /// `Box::new(|s| !s.uri.contains("preloading"))`.
     pub fn new(
         request: Request,
         http: Client,
-        filter: Option<fn(&str) -> bool>,
-    ) -> (Self, UnboundedReceiver<HlsQueue>) {
-        let (tx, rx) = unbounded_channel();
-        let master_url = request
-            .url()
-            .join(".")
-            .expect("Could not join url with '.'.");
+        filter: Option<SegmentFilter>,
+    ) -> (Self, Receiver<HlsQueue>) {
+        let (tx, rx) = channel(HLS_QUEUE_SIZE);
         (
             HlsWatch {
                 tx,
                 request,
                 http,
-                links: PatriciaSet::new(),
-                master_url,
-                timeout: Duration::from_secs(10),
-                fail_counter: 0,
+                seen: SeenSegments::new(),
+                reload: ReloadConfig::default(),
                 filter,
+                low_latency: false,
+                refresh: None,
+                low_latency_cursor: None,
+                stats: StatsHandle::new(),
             },
             rx,
         )
     }
 
     pub async fn run(mut self) -> Result<(), Error> {
+        let mut timer = ReloadTimer::new(self.reload);
+
         loop {
-            if self.fail_counter > HLS_MAX_RETRIES {
-                // There have either been errors or no new segments
-                // for `HLS_MAX_RETRIES` times the segment duration given
-                // in the m3u8 playlist file.
-                if self.tx.send(HlsQueue::StreamOver).is_err() {
-                    return Err(Error::TIO(std::io::Error::last_os_error()));
+            if timer.over()? {
+                if self.tx.send(HlsQueue::StreamOver).await.is_err() {
+                    return Err(Error::Stopped);
                 };
                 break;
             }
 
             // Clone the request so we can reuse it in the loop.
-            let req = clone_request(&self.request, self.timeout);
+            let req = clone_request(&self.request, self.reload.timeout);
+            let url = req.url().clone();
+            timer.start();
+            let reload = Instant::now();
             let res = match self.http.execute(req).await {
+                Ok(r) => check_status(r),
+                Err(e) => Err(e.into()),
+            };
+            self.stats.request(&url, res.is_ok());
+            let res = match res {
                 Ok(r) => r,
                 Err(e) => {
                     warn!("[HLS] Playlist download failed!\n{}", e);
-                    self.fail_counter += 1;
+                    self.refresh_expired(&e).await;
+                    timer.failed(Some(e)).await;
                     continue;
                 }
             };
 
+            // Segments are relative to the playlist after redirects.
+            let playlist_url = res.url().clone();
             let m3u8_string = match res.text().await {
                 Ok(t) => t,
                 Err(e) => {
                     warn!("[HLS] Playlist text failed!\n{}", e);
-                    self.fail_counter += 1;
+                    timer.failed(Some(e.into())).await;
                     continue;
                 }
             };
-
-            let mut m3u8_parser = MediaPlaylist::builder();
+            self.stats.playlist_reloaded(reload.elapsed());
+
+            if self.low_latency && is_low_latency(&m3u8_string) {
+                debug!("[HLS] Following the playlist in low latency mode.");
+                let watch = LowLatencyWatch {
+                    tx: &self.tx,
+                    http: &self.http,
+                    headers: self.request.headers(),
+                    filter: self.filter.as_ref(),
+                    url: playlist_url,
+                    reload: self.reload,
+                    stats: &self.stats,
+                    refresh: self.refresh.is_some(),
+                    cursor: &mut self.low_latency_cursor,
+                };
+                match watch.run().await? {
+                    // The playlist is loaded again with the new request.
+                    Some(e) if self.refresh_expired(&e).await => continue,
+                    Some(e) => return Err(e),
+                    None => return Ok(()),
+                }
+            }
 
             // Allow excess segment duration because a lot of video sites have
             // not very high quality m3u8 playlists, where the video segments,
             // may be longer than what the file specifies as max.
-            m3u8_parser.allowable_excess_duration(Duration::from_secs(10));
-
-            let m3u8 = match m3u8_parser.parse(&m3u8_string) {
+            let m3u8 = match self.reload.parser().parse(&m3u8_string) {
                 Ok(p) => p,
                 Err(e) => {
-                    warn!("[HLS] Parsing failed!\n{}", e);
+                    let e = Error::playlist(&playlist_url, &m3u8_string, e.into());
+                    warn!("[HLS] {}", e);
                     trace!("[HLS]\n{}", &m3u8_string);
-                    self.fail_counter += 1;
+                    timer.failed(Some(e)).await;
                     continue;
                 }
             };
-
-            // Get the target duration of a segment
-            let target_duration = m3u8.target_duration;
-
-            // Makes a iterator with the url parts from the playlist
-            for e in m3u8.segments.iter().map(|(_, e)| e.uri().trim()) {
-                trace!("[HLS] Tries to inserts: {}", e);
-                // Check if we have the segment in our set already
-                if self.links.insert(e) {
-                    // Reset the counter as we got a new segment.
-                    self.fail_counter = 0;
-
-                    // Construct a url from the master and the segment.
-                    let url_formatted = if let Ok(u) = Url::parse(e) {
-                        u
-                    } else {
-                        // Attempt to parse the url as a relative url.
-                        Url::parse(&format!("{}{}", self.master_url.as_str(), &e)).expect(
-                            "The m3u8 does not currently work with stream_lib, \
-                             please report the issue on the github repo, with an \
-                             example of the file if possible.",
-                        )
-                    };
-
-                    // Check that the filter runs.
-                    if self.filter.map_or(true, |f| f(e)) {
-                        debug!("[HLS] Adds {}!", url_formatted);
-                        // Add the segment to the queue.
-                        if self.tx.send(HlsQueue::Url(url_formatted)).is_err() {
-                            return Err(Error::TIO(std::io::Error::last_os_error()));
-                        };
-                    }
-                }
-            }
-
-            if m3u8.has_end_list {
-                tracing::debug!("List has end, no more segments expected.");
+            timer.loaded(m3u8.target_duration);
+
+            let over = queue_playlist(
+                &self.tx,
+                &mut self.seen,
+                &mut timer,
+                &m3u8,
+                &playlist_url,
+                self.filter.as_ref(),
+                &self.stats,
+            )
+            .await?;
+            if over {
                 break;
             }
-
-            trace!("[HLS] Sleeps for {:#?}", target_duration);
-            // Sleeps for the target duration.
-            tokio::time::sleep(target_duration).await;
-            self.fail_counter += 1;
         }
 
         Ok(())
     }
+
+    /// Asks for a new playlist request if `error` says the current one
+    /// expired, returns `true` if it was replaced.
+    async fn refresh_expired(&mut self, error: &Error) -> bool {
+        match &self.refresh {
+            Some(refresh) => refresh.refresh_expired(error, &mut self.request).await,
+            None => false,
+        }
+    }
+}
+
+/// Queues the segments of a loaded media playlist that were not queued
+/// before, with a gap for the ones that left it unseen, and then waits for
+/// the next reload.
+///
+/// Returns `true` if the stream is over.
+pub(super) async fn queue_playlist(
+    tx: &Sender<HlsQueue>,
+    seen: &mut SeenSegments,
+    timer: &mut ReloadTimer,
+    m3u8: &MediaPlaylist<'_>,
+    playlist_url: &Url,
+    filter: Option<&SegmentFilter>,
+    stats: &StatsHandle,
+) -> Result<bool, Error> {
+    if let Some((segments, duration)) = seen.missing(m3u8) {
+        warn!(
+            "[HLS] {} segments fell out of the playlist before they were seen.",
+            segments
+        );
+        let gap = HlsQueue::Gap { segments, duration };
+        if tx.send(gap).await.is_err() {
+            return Err(Error::Stopped);
+        }
+    }
+
+    let times = program_date_times(m3u8);
+
+    // Only segments that have not been queued before.
+    for segment in seen.new_segments(m3u8) {
+        trace!("[HLS] New segment: {}", segment_id(segment));
+        timer.progress();
+
+        let time = times.get(&segment.number()).copied();
+        let segment = match Segment::new(playlist_url, segment, time) {
+            Ok(segment) => segment,
+            Err(error) => {
+                // One bad segment does not end the recording.
+                warn!("[HLS] Skipping segment {}: {}", segment.number(), error);
+                let duration = segment.duration.duration();
+                stats.queued(duration);
+                let failed = HlsQueue::SegmentFailed {
+                    url: playlist_url.clone(),
+                    duration,
+                    error,
+                };
+                if tx.send(failed).await.is_err() {
+                    return Err(Error::Stopped);
+                }
+                continue;
+            }
+        };
+
+        // Check that the filter runs.
+        if filter.is_none_or(|f| f(&segment.info)) {
+            debug!("[HLS] Adds {}!", segment.url);
+            stats.queued(segment.info.duration);
+            // Add the segment to the queue.
+            if tx.send(HlsQueue::Segment(Box::new(segment))).await.is_err() {
+                return Err(Error::Stopped);
+            };
+        }
+    }
+
+    if m3u8.has_end_list {
+        tracing::debug!("List has end, no more segments expected.");
+        return Ok(true);
+    }
+
+    if timer.stalled() {
+        if tx.send(HlsQueue::StreamOver).await.is_err() {
+            return Err(Error::Stopped);
+        };
+        return Ok(true);
+    }
+
+    timer.wait(m3u8.target_duration).await;
+    Ok(false)
 }
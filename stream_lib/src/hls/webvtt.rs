@@ -0,0 +1,380 @@
+use std::{collections::VecDeque, fmt::Write as _, str::FromStr, time::Duration};
+
+use bytes::Bytes;
+use tracing::{debug, warn};
+
+/// The MPEG-2 timestamps of `X-TIMESTAMP-MAP` run at 90 kHz.
+const MPEGTS_CLOCK: f64 = 90_000.0;
+
+/// MPEG-2 timestamps are 33 bits and wrap around after about 26.5 hours.
+const MPEGTS_WRAP: i64 = 1 << 33;
+
+/// How many cues are remembered to skip the ones repeated by the next
+/// segment.
+const CUE_WINDOW: usize = 64;
+
+/// The file format subtitle renditions are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubtitleFormat {
+    #[default]
+    WebVtt,
+    SubRip,
+}
+
+impl SubtitleFormat {
+    /// The file extension of the format, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            SubtitleFormat::WebVtt => "vtt",
+            SubtitleFormat::SubRip => "srt",
+        }
+    }
+}
+
+impl FromStr for SubtitleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "vtt" | "webvtt" => Ok(SubtitleFormat::WebVtt),
+            "srt" | "subrip" => Ok(SubtitleFormat::SubRip),
+            other => Err(format!("Unknown subtitle format: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Cue {
+    /// Seconds on the timeline of the stream.
+    start: f64,
+    end: f64,
+    settings: String,
+    text: String,
+}
+
+impl Cue {
+    /// The times of a repeated cue can differ slightly after being mapped
+    /// through different timestamp maps.
+    fn same_as(&self, other: &Cue) -> bool {
+        (self.start - other.start).abs() < 0.001
+            && (self.end - other.end).abs() < 0.001
+            && self.text == other.text
+    }
+}
+
+/// Turns the WebVTT segments of a subtitle rendition into a single file.
+///
+/// Cue times are moved onto the timeline of the stream with the
+/// `X-TIMESTAMP-MAP` header of each segment, and then rebased so the start
+/// of the recording is at zero. Cues that are repeated by the following
+/// segment are only written once.
+pub(crate) struct SubtitleStitcher {
+    format: SubtitleFormat,
+    /// Stream time of the start of the recording, known once a segment
+    /// with cues or a timestamp map has been seen.
+    origin: Option<f64>,
+    /// How much of the recording came before the origin was known.
+    elapsed: Duration,
+    /// Seconds from the start of the recording to the first subtitle
+    /// segment.
+    offset: f64,
+    last_mpegts: Option<i64>,
+    wraps: i64,
+    recent: VecDeque<Cue>,
+    written: usize,
+}
+
+impl SubtitleStitcher {
+    pub(crate) fn new(format: SubtitleFormat) -> Self {
+        SubtitleStitcher {
+            format,
+            origin: None,
+            elapsed: Duration::ZERO,
+            offset: 0.0,
+            last_mpegts: None,
+            wraps: 0,
+            recent: VecDeque::new(),
+            written: 0,
+        }
+    }
+
+    /// The first subtitle segment starts `offset` seconds into the recording,
+    /// negative if it starts before it.
+    pub(crate) fn start_at(&mut self, offset: f64) {
+        if self.origin.is_none() {
+            self.offset = offset;
+        }
+    }
+
+    /// Missing or empty segments still take up time in the recording.
+    pub(crate) fn gap(&mut self, duration: Duration) {
+        if self.origin.is_none() {
+            self.elapsed += duration;
+        }
+    }
+
+    /// Converts a WebVTT segment of `duration` into the cues it adds to the
+    /// output, the file header is part of the first call.
+    pub(crate) fn push(&mut self, segment: &[u8], duration: Duration) -> Bytes {
+        let text = String::from_utf8_lossy(segment).replace("\r\n", "\n");
+        let mut out = String::new();
+        if self.written == 0 && self.format == SubtitleFormat::WebVtt {
+            out.push_str("WEBVTT\n\n");
+        }
+
+        let mut blocks = text.split("\n\n").map(|b| b.trim_matches('\n'));
+        let header = blocks.next().unwrap_or_default();
+        if !header.starts_with("WEBVTT") && !header.starts_with("\u{feff}WEBVTT") {
+            warn!("[HLS] Subtitle segment is not WebVTT, skipping it.");
+            self.gap(duration);
+            return Bytes::new();
+        }
+
+        // Where `LOCAL` of the cues lands on the stream timeline.
+        let map = header
+            .lines()
+            .find_map(|l| l.strip_prefix("X-TIMESTAMP-MAP="))
+            .and_then(|m| self.parse_map(m));
+        let offset = map.map_or(0.0, |(mpegts, local)| mpegts - local);
+
+        let cues: Vec<Cue> = blocks
+            .filter_map(parse_cue)
+            .map(|c| Cue {
+                start: c.start + offset,
+                end: c.end + offset,
+                ..c
+            })
+            .collect();
+
+        let origin = match self.origin {
+            Some(origin) => origin,
+            None => {
+                let first = cues.iter().map(|c| c.start).reduce(f64::min);
+                let segment_start = match (map, first) {
+                    // The map usually points at the start of the segment,
+                    // unless it is the same for every segment and the cues
+                    // count from the start of the stream.
+                    (Some((mpegts, _)), Some(first))
+                        if (first - mpegts).abs() <= duration.as_secs_f64() =>
+                    {
+                        mpegts
+                    }
+                    (_, Some(first)) => first,
+                    (Some((mpegts, _)), None) => mpegts,
+                    (None, None) => {
+                        self.gap(duration);
+                        return Bytes::new();
+                    }
+                };
+                let origin = segment_start - self.elapsed.as_secs_f64() - self.offset;
+                debug!(
+                    "[HLS] The recording starts at {:.3}s of the stream.",
+                    origin
+                );
+                self.origin = Some(origin);
+                origin
+            }
+        };
+
+        for cue in cues {
+            if self.recent.iter().any(|c| c.same_as(&cue)) {
+                continue;
+            }
+            self.recent.push_back(cue.clone());
+            if self.recent.len() > CUE_WINDOW {
+                self.recent.pop_front();
+            }
+
+            let (start, end) = (cue.start - origin, cue.end - origin);
+            if end <= 0.0 {
+                continue;
+            }
+            self.written += 1;
+            self.write_cue(&mut out, start.max(0.0), end, &cue);
+        }
+
+        if self.written == 0 {
+            // Nothing to show yet, the header is written with the first cue.
+            return Bytes::new();
+        }
+        Bytes::from(out)
+    }
+
+    /// Parses `MPEGTS:900000,LOCAL:00:00:00.000` into seconds.
+    fn parse_map(&mut self, map: &str) -> Option<(f64, f64)> {
+        let mut mpegts = None;
+        let mut local = None;
+        for part in map.split(',') {
+            match part.trim().split_once(':') {
+                Some(("MPEGTS", v)) => mpegts = v.parse::<i64>().ok(),
+                Some(("LOCAL", v)) => local = parse_timestamp(v),
+                _ => {}
+            }
+        }
+        let mpegts = mpegts?;
+
+        // Keep counting when the 33 bit timestamp wraps around.
+        if let Some(last) = self.last_mpegts {
+            if last - mpegts > MPEGTS_WRAP / 2 {
+                self.wraps += 1;
+            }
+        }
+        self.last_mpegts = Some(mpegts);
+
+        let mpegts = (mpegts + self.wraps * MPEGTS_WRAP) as f64 / MPEGTS_CLOCK;
+        Some((mpegts, local.unwrap_or(0.0)))
+    }
+
+    fn write_cue(&self, out: &mut String, start: f64, end: f64, cue: &Cue) {
+        match self.format {
+            SubtitleFormat::WebVtt => {
+                let _ = write!(
+                    out,
+                    "{} --> {}",
+                    format_timestamp(start, '.'),
+                    format_timestamp(end, '.')
+                );
+                if !cue.settings.is_empty() {
+                    out.push(' ');
+                    out.push_str(&cue.settings);
+                }
+                let _ = write!(out, "\n{}\n\n", cue.text);
+            }
+            SubtitleFormat::SubRip => {
+                let _ = write!(
+                    out,
+                    "{}\n{} --> {}\n{}\n\n",
+                    self.written,
+                    format_timestamp(start, ','),
+                    format_timestamp(end, ','),
+                    strip_tags(&cue.text)
+                );
+            }
+        }
+    }
+}
+
+/// Parses a cue block, `NOTE`, `STYLE` and `REGION` blocks are skipped.
+fn parse_cue(block: &str) -> Option<Cue> {
+    let mut lines = block.lines();
+    let mut timing = lines.next()?;
+    if !timing.contains("-->") {
+        // The first line is the cue identifier.
+        timing = lines.next()?;
+    }
+
+    let (start, rest) = timing.split_once("-->")?;
+    let rest = rest.trim();
+    let (end, settings) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    Some(Cue {
+        start: parse_timestamp(start.trim())?,
+        end: parse_timestamp(end)?,
+        settings: settings.trim().to_string(),
+        text: lines.collect::<Vec<_>>().join("\n"),
+    })
+}
+
+/// Parses `hh:mm:ss.ttt` or `mm:ss.ttt` into seconds.
+fn parse_timestamp(s: &str) -> Option<f64> {
+    let (rest, millis) = s.split_once('.')?;
+    let mut seconds = 0.0;
+    for part in rest.split(':') {
+        seconds = seconds * 60.0 + part.parse::<u64>().ok()? as f64;
+    }
+    Some(seconds + millis.parse::<u64>().ok()? as f64 / 1000.0)
+}
+
+fn format_timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+/// SubRip only knows the `b`, `i` and `u` tags, everything else such as
+/// voice and class spans is removed.
+fn strip_tags(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        out.push_str(&rest[..open]);
+        let Some(close) = rest[open..].find('>') else {
+            rest = &rest[open..];
+            break;
+        };
+        let tag = &rest[open..open + close + 1];
+        let name = tag.trim_start_matches(['<', '/']).trim_end_matches('>');
+        if matches!(name, "b" | "i" | "u") {
+            out.push_str(tag);
+        }
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", "\u{a0}")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(mpegts: u64, cue: &str) -> Vec<u8> {
+        format!(
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000\n\n{}\nHello\n",
+            mpegts, cue
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn times_cues_from_the_start_of_the_recording() {
+        let mut stitcher = SubtitleStitcher::new(SubtitleFormat::WebVtt);
+        // The subtitle playlist starts four seconds into the recording.
+        stitcher.start_at(4.0);
+        let out = stitcher.push(
+            &segment(900_000, "00:00:01.000 --> 00:00:02.000"),
+            Duration::from_secs(4),
+        );
+        assert_eq!(out, "WEBVTT\n\n00:00:05.000 --> 00:00:06.000\nHello\n\n");
+    }
+
+    #[test]
+    fn writes_repeated_cues_once() {
+        let mut stitcher = SubtitleStitcher::new(SubtitleFormat::WebVtt);
+        // The cue spans both segments, so both of them list it.
+        let cue = "00:00:03.000 --> 00:00:05.000";
+        let first = stitcher.push(&segment(900_000, cue), Duration::from_secs(4));
+        let second = format!(
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:1260000,LOCAL:00:00:04.000\n\n{}\nHello\n",
+            cue
+        );
+        let second = stitcher.push(second.as_bytes(), Duration::from_secs(4));
+        assert_eq!(first, "WEBVTT\n\n00:00:03.000 --> 00:00:05.000\nHello\n\n");
+        assert!(second.is_empty());
+    }
+
+    #[test]
+    fn writes_subrip() {
+        let mut stitcher = SubtitleStitcher::new(SubtitleFormat::SubRip);
+        let vtt =
+            "WEBVTT\n\n1\n00:01.500 --> 00:02.000 align:start\n<v Anna>Hej</v> <i>&amp;</i>\n";
+        let out = stitcher.push(vtt.as_bytes(), Duration::from_secs(4));
+        // Without a timestamp map the first cue starts the file.
+        assert_eq!(out, "1\n00:00:00,000 --> 00:00:00,500\nHej <i>&</i>\n\n");
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("01:02:03.004"), Some(3723.004));
+        assert_eq!(parse_timestamp("02:03.500"), Some(123.5));
+        assert_eq!(parse_timestamp("02:03"), None);
+        assert_eq!(format_timestamp(3723.004, ','), "01:02:03,004");
+    }
+}
@@ -1,53 +1,141 @@
 //! This is a small tool to download streams
-//! It currently supports chunked streams and HLS.
+//! It currently supports chunked streams, HLS and MPEG-DASH.
 
+mod builder;
+mod chunked;
+mod config;
+mod dash;
 mod download_stream;
 mod error;
+mod flv;
 mod hls;
+mod rate;
+mod refresh;
+mod retry;
+mod stats;
+mod variant;
 
-use std::time::Duration;
+pub use crate::builder::StreamBuilder;
+pub use crate::config::DownloadConfig;
+pub use crate::download_stream::{BufferSize, DownloadStream, Event, Overflow};
+pub use crate::error::{Error, ErrorKind};
+pub use crate::hls::{SegmentFilter, SegmentInfo, SubtitleFormat};
+pub use crate::rate::RateLimiter;
+pub use crate::refresh::RequestRefresh;
+pub use crate::retry::RetryPolicy;
+pub use crate::stats::{Histogram, HostStats, Stats, StatsHandle};
+pub use crate::variant::{
+    RenditionInfo, RenditionSelector, VariantFn, VariantInfo, VariantSelector,
+};
 
-pub use crate::download_stream::{DownloadStream, Event};
-pub use crate::error::Error;
-
-use crate::hls::HlsDownloader;
-use hls::download_to_file;
 use reqwest::{Client, Request};
 
+#[deprecated(note = "use `StreamBuilder::new(http, request).hls()`")]
 pub fn download_hls(
     http: Client,
     request: Request,
-    filter: Option<fn(&str) -> bool>,
+    filter: Option<SegmentFilter>,
 ) -> DownloadStream {
-    HlsDownloader::new(request, http, filter).download()
+    DownloadConfig::default().download_hls(http, request, filter)
 }
 
+#[deprecated(
+    note = "use `StreamBuilder::new(http, request).variant(VariantSelector::Name(name)).hls()`"
+)]
 pub fn download_hls_named(
     http: Client,
     request: Request,
     name: String,
-    filter: Option<fn(&str) -> bool>,
+    filter: Option<SegmentFilter>,
 ) -> DownloadStream {
-    HlsDownloader::new_named(request, http, name, filter).download()
+    DownloadConfig::default().download_hls_named(http, request, name, filter)
 }
 
+#[deprecated(
+    note = "use `StreamBuilder::new(http, request).variant(VariantSelector::First).hls()`"
+)]
 pub fn download_hls_master_first(
     http: Client,
     request: Request,
-    filter: Option<fn(&str) -> bool>,
+    filter: Option<SegmentFilter>,
+) -> DownloadStream {
+    DownloadConfig::default().download_hls_master_first(http, request, filter)
+}
+
+/// Downloads the variant of a master playlist picked by `selector`.
+#[deprecated(note = "use `StreamBuilder::new(http, request).variant(selector).hls()`")]
+pub fn download_hls_variant(
+    http: Client,
+    request: Request,
+    selector: VariantSelector,
+    filter: Option<SegmentFilter>,
+) -> DownloadStream {
+    DownloadConfig::default().download_hls_variant(http, request, selector, filter)
+}
+
+/// Downloads an alternative audio rendition of the variant picked by
+/// `selector`, see [`DownloadConfig::download_hls_audio`].
+#[deprecated(note = "use `StreamBuilder::new(http, request).variant(selector).hls_audio(audio)`")]
+pub fn download_hls_audio(
+    http: Client,
+    request: Request,
+    selector: VariantSelector,
+    audio: RenditionSelector,
+    filter: Option<SegmentFilter>,
 ) -> DownloadStream {
-    HlsDownloader::new_master_first(request, http, filter).download()
+    DownloadConfig::default().download_hls_audio(http, request, selector, audio, filter)
 }
 
+/// Downloads a subtitle rendition of the variant picked by `selector` as a
+/// single file, see [`DownloadConfig::download_hls_subtitles`].
+#[deprecated(
+    note = "use `StreamBuilder::new(http, request).variant(selector).hls_subtitles(subtitles, format)`"
+)]
+pub fn download_hls_subtitles(
+    http: Client,
+    request: Request,
+    selector: VariantSelector,
+    subtitles: RenditionSelector,
+    format: SubtitleFormat,
+) -> DownloadStream {
+    DownloadConfig::default().download_hls_subtitles(http, request, selector, subtitles, format)
+}
+
+/// Downloads the representation picked by `selector` from a MPEG-DASH
+/// manifest, see [`DownloadConfig::download_dash`].
+#[deprecated(note = "use `StreamBuilder::new(http, request).variant(selector).dash()`")]
+pub fn download_dash(http: Client, request: Request, selector: VariantSelector) -> DownloadStream {
+    DownloadConfig::default().download_dash(http, request, selector)
+}
+
+/// Downloads an audio adaptation set of a MPEG-DASH manifest, see
+/// [`DownloadConfig::download_dash_audio`].
+#[deprecated(note = "use `StreamBuilder::new(http, request).dash_audio(audio)`")]
+pub fn download_dash_audio(
+    http: Client,
+    request: Request,
+    audio: RenditionSelector,
+) -> DownloadStream {
+    DownloadConfig::default().download_dash_audio(http, request, audio)
+}
+
+/// Guesses the file extension of a HLS stream, `"mp4"` for fragmented MP4
+/// streams using `EXT-X-MAP` and `"ts"` otherwise, see
+/// [`DownloadConfig::hls_extension`].
+pub async fn hls_extension(http: Client, request: Request) -> Result<&'static str, Error> {
+    DownloadConfig::default()
+        .hls_extension(http, request, None)
+        .await
+}
+
+#[deprecated(note = "use `StreamBuilder::new(http, request).chunked()`")]
 pub fn download_chunked(http: Client, request: Request) -> DownloadStream {
-    let (dl, tx) = DownloadStream::new();
-
-    tokio::spawn(download_to_file(
-        http,
-        request,
-        tx,
-        None,
-        Some(Duration::from_secs(60)),
-    ));
-    dl
+    DownloadConfig::default().download_chunked(http, request)
+}
+
+/// Downloads a live stream served over a single http response, see
+/// [`DownloadConfig::download_live_chunked`].
+#[deprecated(note = "use `StreamBuilder::new(http, request).live_chunked()`")]
+pub fn download_live_chunked(http: Client, request: Request) -> DownloadStream {
+    DownloadConfig::default().download_live_chunked(http, request)
 }
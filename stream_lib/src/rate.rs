@@ -0,0 +1,96 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Caps how fast downloads receive data, using a token bucket.
+///
+/// Clones share the same budget, so one limiter set on the
+/// [`DownloadConfig`](crate::DownloadConfig) of several downloads caps them
+/// together. A limiter made [`within`](RateLimiter::within) another one also
+/// counts towards it, to cap every download and all of them together.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    parent: Option<Box<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Most bytes that can be saved up.
+    burst: f64,
+    /// Bytes that can be received right away, negative when the downloads
+    /// are in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Allows `bytes_per_second` on average, with bursts of up to a second
+    /// worth of data after a pause.
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                burst: rate,
+                tokens: rate,
+                refilled: Instant::now(),
+            })),
+            parent: None,
+        }
+    }
+
+    /// Sets how many bytes can be received at once after a pause.
+    pub fn with_burst(self, bytes: u64) -> Self {
+        if let Ok(mut bucket) = self.bucket.lock() {
+            bucket.burst = bytes.max(1) as f64;
+            bucket.tokens = bucket.tokens.min(bucket.burst);
+        }
+        self
+    }
+
+    /// Counts everything received through this limiter towards `parent`
+    /// too, e.g. a limiter per download within a global one.
+    pub fn within(mut self, parent: &RateLimiter) -> Self {
+        self.parent = Some(Box::new(parent.clone()));
+        self
+    }
+
+    /// Waits until `bytes` that were just received fit the rate of this
+    /// limiter and its parents.
+    pub(crate) async fn acquire(&self, bytes: usize) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            let wait = match current.bucket.lock() {
+                Ok(mut bucket) => bucket.take(bytes),
+                Err(_) => Duration::ZERO,
+            };
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            limiter = current.parent.as_deref();
+        }
+    }
+}
+
+impl Bucket {
+    /// Takes `bytes` out of the bucket, even if it goes into debt, and
+    /// returns how long it takes until the debt is paid back.
+    fn take(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst) - bytes as f64;
+        self.refilled = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
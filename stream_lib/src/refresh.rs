@@ -0,0 +1,64 @@
+use std::{fmt, future::Future, sync::Arc};
+
+use futures_util::future::BoxFuture;
+use reqwest::Request;
+use tracing::{info, warn};
+
+use crate::{Error, ErrorKind};
+
+type RefreshFn = dyn Fn() -> BoxFuture<'static, Option<Request>> + Send + Sync;
+
+/// Makes a new request for a download when the server rejects the current
+/// one, e.g. because a signed url or access token expired.
+///
+/// The download continues with the new request in the same
+/// [`DownloadStream`](crate::DownloadStream), segments that were already
+/// downloaded are not downloaded again.
+#[derive(Clone)]
+pub struct RequestRefresh(Arc<RefreshFn>);
+
+impl RequestRefresh {
+    /// `refresh` returns `None` if no new request could be made, the
+    /// download then fails like it would without a refresh.
+    pub fn new<F, Fut>(refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<Request>> + Send + 'static,
+    {
+        RequestRefresh(Arc::new(move || Box::pin(refresh())))
+    }
+
+    /// Replaces `request` with a new one if `error` says it has expired,
+    /// returns `true` if it was replaced.
+    pub(crate) async fn refresh_expired(&self, error: &Error, request: &mut Request) -> bool {
+        if error.kind() != ErrorKind::Expired {
+            return false;
+        }
+        match (self.0)().await {
+            Some(new) => {
+                info!("The url of the stream expired, continuing with a new one.");
+                *request = new;
+                true
+            }
+            None => {
+                warn!("The url of the stream expired and no new one could be made.");
+                false
+            }
+        }
+    }
+
+    /// Makes a new request whether or not the current one expired.
+    pub(crate) async fn refresh(&self) -> Option<Request> {
+        let new = (self.0)().await;
+        if new.is_none() {
+            warn!("No new url could be made for the stream, keeps the old one.");
+        }
+        new
+    }
+}
+
+impl fmt::Debug for RequestRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestRefresh(..)")
+    }
+}
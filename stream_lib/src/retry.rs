@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    Response, StatusCode,
+};
+
+use crate::error::Error;
+
+/// Longest `Retry-After` that is waited for, longer ones are most likely
+/// not meant for a live stream.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// How often a failed segment download is retried before it is given up on.
+///
+/// The delay between two attempts starts at `initial_backoff` and doubles
+/// after every attempt up to `max_backoff`. Servers answering with
+/// `429 Too Many Requests` or `503 Service Unavailable` and a `Retry-After`
+/// header are waited for at least as long as they ask, up to five minutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt, 0 disables retrying.
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Returns how long to wait before retry number `attempt` (starting at
+    /// 0), or `None` if the error should not be retried.
+    pub(crate) fn delay(&self, attempt: u32, error: &Error) -> Option<Duration> {
+        if attempt >= self.max_retries || !error.is_retryable() {
+            return None;
+        }
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+
+        Some(match error {
+            Error::Status {
+                retry_after: Some(after),
+                ..
+            } => (*after).min(MAX_RETRY_AFTER).max(backoff),
+            _ => backoff,
+        })
+    }
+}
+
+/// Parses the `Retry-After` header of `429` and `503` responses.
+///
+/// Only the number of seconds form is supported, a http date falls back to
+/// the normal backoff.
+pub(crate) fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+
+    let secs = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+/// Turns an unsuccessful response into [`Error::Status`], so error pages are
+/// never mistaken for media.
+pub(crate) fn check_status(res: Response) -> Result<Response, Error> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    Err(Error::Status {
+        url: res.url().clone(),
+        status,
+        retry_after: retry_after(status, res.headers()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn too_many_requests(retry_after: Option<Duration>) -> Error {
+        Error::Status {
+            url: "http://example.com/1.ts".parse().unwrap(),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy::default();
+        let error = too_many_requests(None);
+        assert_eq!(policy.delay(0, &error), Some(Duration::from_millis(500)));
+        assert_eq!(policy.delay(2, &error), Some(Duration::from_secs(2)));
+        assert_eq!(policy.delay(3, &error), None);
+    }
+
+    #[test]
+    fn waits_at_least_retry_after() {
+        let policy = RetryPolicy::default();
+        let error = too_many_requests(Some(Duration::from_secs(30)));
+        assert_eq!(policy.delay(0, &error), Some(Duration::from_secs(30)));
+
+        let error = too_many_requests(Some(Duration::ZERO));
+        assert_eq!(policy.delay(1, &error), Some(Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+
+/// A handle to the statistics of a download, taken from
+/// [`DownloadStream::stats`](crate::DownloadStream::stats).
+///
+/// The handle stays valid after the stream is dropped, so the final numbers
+/// can still be read.
+#[derive(Debug, Clone)]
+pub struct StatsHandle {
+    stats: Arc<Mutex<Stats>>,
+    started: Instant,
+}
+
+/// The statistics of a download at one point in time.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Time since the download started.
+    pub elapsed: Duration,
+    /// Bytes of segments and chunked streams received so far.
+    pub bytes: u64,
+    /// Segments that were downloaded.
+    pub segments: u64,
+    /// Segments that could not be downloaded, even after retrying.
+    pub failed_segments: u64,
+    /// Requests that were made again after they failed, including
+    /// reconnects and resumes of chunked downloads.
+    pub retries: u64,
+    /// Successful reloads of the playlist or manifest.
+    pub playlist_reloads: u64,
+    /// How long downloading a segment or init section took, not counting
+    /// failed attempts.
+    pub segment_latency: Histogram,
+    /// How long reloading the playlist or manifest took, which is how far
+    /// every refresh lags behind the server.
+    pub playlist_latency: Histogram,
+    /// Duration of the segments that were found in the playlist but are not
+    /// downloaded yet, how far the recording is behind the live edge.
+    pub live_edge_distance: Duration,
+    /// Requests and errors by host, to spot a bad CDN.
+    pub hosts: BTreeMap<String, HostStats>,
+}
+
+/// Requests made to a single host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostStats {
+    pub requests: u64,
+    /// Requests that failed, with an unsuccessful status or without a
+    /// response.
+    pub errors: u64,
+}
+
+impl HostStats {
+    /// The share of requests that failed, between 0 and 1.
+    pub fn error_rate(&self) -> f64 {
+        match self.requests {
+            0 => 0.0,
+            requests => self.errors as f64 / requests as f64,
+        }
+    }
+}
+
+/// Counts durations in buckets, like a prometheus histogram.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// `buckets[i]` counts the durations up to `Histogram::BOUNDS[i]`, the
+    /// last bucket the longer ones. The buckets are not cumulative.
+    pub buckets: [u64; Histogram::BOUNDS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+    pub max: Duration,
+}
+
+impl Histogram {
+    /// Upper bounds of the buckets.
+    pub const BOUNDS: [Duration; 9] = [
+        Duration::from_millis(50),
+        Duration::from_millis(100),
+        Duration::from_millis(250),
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+        Duration::from_millis(2500),
+        Duration::from_secs(5),
+        Duration::from_secs(10),
+        Duration::from_secs(30),
+    ];
+
+    /// The average duration, `None` if nothing was counted.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|c| *c > 0)?;
+        Some(self.sum / count)
+    }
+
+    fn record(&mut self, duration: Duration) {
+        let bucket = Self::BOUNDS
+            .iter()
+            .position(|bound| duration <= *bound)
+            .unwrap_or(Self::BOUNDS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += duration;
+        self.max = self.max.max(duration);
+    }
+}
+
+impl StatsHandle {
+    pub(crate) fn new() -> Self {
+        StatsHandle {
+            stats: Arc::new(Mutex::new(Stats {
+                elapsed: Duration::ZERO,
+                bytes: 0,
+                segments: 0,
+                failed_segments: 0,
+                retries: 0,
+                playlist_reloads: 0,
+                segment_latency: Histogram::default(),
+                playlist_latency: Histogram::default(),
+                live_edge_distance: Duration::ZERO,
+                hosts: BTreeMap::new(),
+            })),
+            started: Instant::now(),
+        }
+    }
+
+    /// Copies the current statistics.
+    pub fn snapshot(&self) -> Stats {
+        let elapsed = self.started.elapsed();
+        self.update(|stats| Stats {
+            elapsed,
+            ..stats.clone()
+        })
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut Stats) -> T) -> T {
+        // The statistics stay usable if a task panicked while updating them.
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut stats)
+    }
+
+    /// Counts a request to the host of `url`.
+    pub(crate) fn request(&self, url: &Url, ok: bool) {
+        let host = url.host_str().unwrap_or_default().to_string();
+        self.update(|stats| {
+            let host = stats.hosts.entry(host).or_default();
+            host.requests += 1;
+            host.errors += u64::from(!ok);
+        });
+    }
+
+    pub(crate) fn retry(&self) {
+        self.update(|stats| stats.retries += 1);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.update(|stats| stats.bytes += bytes as u64);
+    }
+
+    pub(crate) fn playlist_reloaded(&self, latency: Duration) {
+        self.update(|stats| {
+            stats.playlist_reloads += 1;
+            stats.playlist_latency.record(latency);
+        });
+    }
+
+    pub(crate) fn downloaded(&self, latency: Duration) {
+        self.update(|stats| stats.segment_latency.record(latency));
+    }
+
+    /// A segment of `duration` was found in the playlist.
+    pub(crate) fn queued(&self, duration: Duration) {
+        self.update(|stats| stats.live_edge_distance += duration);
+    }
+
+    /// A queued segment of `duration` was written, or given up on.
+    pub(crate) fn segment_done(&self, duration: Duration, ok: bool) {
+        self.update(|stats| {
+            stats.live_edge_distance = stats.live_edge_distance.saturating_sub(duration);
+            match ok {
+                true => stats.segments += 1,
+                false => stats.failed_segments += 1,
+            }
+        });
+    }
+}
@@ -0,0 +1,216 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+/// The properties of a variant stream that a [`VariantSelector`] chooses
+/// from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariantInfo {
+    /// Peak bitrate in bits per second.
+    pub bandwidth: u64,
+    /// Width and height in pixels.
+    pub resolution: Option<(usize, usize)>,
+    pub frame_rate: Option<f32>,
+    /// RFC 6381 codec strings, e.g. `avc1.64001f` or `mp4a.40.2`.
+    pub codecs: Vec<String>,
+    /// Names of the renditions (`EXT-X-MEDIA`) associated with the variant.
+    pub names: Vec<String>,
+}
+
+/// A user supplied selector, returns the index of the wanted variant.
+pub type VariantFn = Arc<dyn Fn(&[VariantInfo]) -> Option<usize> + Send + Sync>;
+
+/// Picks one of the variants of a master playlist.
+///
+/// Selectors can be parsed from strings, `"best"`, `"worst"`, `"first"`,
+/// resolution ceilings like `"720p"` or `"720p60"` and everything else is
+/// taken as a rendition name.
+#[derive(Clone, Default)]
+pub enum VariantSelector {
+    /// The variant with the highest bandwidth.
+    #[default]
+    Best,
+    /// The variant with the lowest bandwidth.
+    Worst,
+    /// The first variant in the playlist.
+    First,
+    /// The variant associated with a rendition of this name.
+    Name(String),
+    /// The best variant that does not exceed the given height and frame
+    /// rate, falls back to the worst variant if they all do.
+    Max {
+        height: Option<usize>,
+        frame_rate: Option<f32>,
+    },
+    /// The best variant using a codec starting with the given string, e.g.
+    /// `"avc1"` or `"hvc1"`.
+    Codec(String),
+    Custom(VariantFn),
+}
+
+impl VariantSelector {
+    /// Returns the index of the selected variant, or `None` if no variant
+    /// matches.
+    pub fn select(&self, variants: &[VariantInfo]) -> Option<usize> {
+        let best = |it: &mut dyn Iterator<Item = (usize, &VariantInfo)>| {
+            it.max_by_key(|(_, v)| v.bandwidth).map(|(i, _)| i)
+        };
+
+        match self {
+            VariantSelector::Best => best(&mut variants.iter().enumerate()),
+            VariantSelector::Worst => variants
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, v)| v.bandwidth)
+                .map(|(i, _)| i),
+            VariantSelector::First => (!variants.is_empty()).then_some(0),
+            VariantSelector::Name(name) => variants.iter().position(|v| v.names.contains(name)),
+            VariantSelector::Max { height, frame_rate } => {
+                let fits = |v: &VariantInfo| {
+                    let height_fits = match (height, v.resolution) {
+                        (Some(max), Some((_, h))) => h <= *max,
+                        _ => true,
+                    };
+                    let rate_fits = match (frame_rate, v.frame_rate) {
+                        // Allow for rates like 59.94 when asking for 60.
+                        (Some(max), Some(rate)) => rate <= max + 0.5,
+                        _ => true,
+                    };
+                    height_fits && rate_fits
+                };
+                best(&mut variants.iter().enumerate().filter(|(_, v)| fits(v)))
+                    .or_else(|| VariantSelector::Worst.select(variants))
+            }
+            VariantSelector::Codec(codec) => best(
+                &mut variants
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, v)| v.codecs.iter().any(|c| c.starts_with(codec.as_str()))),
+            ),
+            VariantSelector::Custom(f) => f(variants).filter(|i| *i < variants.len()),
+        }
+    }
+}
+
+impl fmt::Debug for VariantSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariantSelector::Best => f.write_str("Best"),
+            VariantSelector::Worst => f.write_str("Worst"),
+            VariantSelector::First => f.write_str("First"),
+            VariantSelector::Name(name) => f.debug_tuple("Name").field(name).finish(),
+            VariantSelector::Max { height, frame_rate } => f
+                .debug_struct("Max")
+                .field("height", height)
+                .field("frame_rate", frame_rate)
+                .finish(),
+            VariantSelector::Codec(codec) => f.debug_tuple("Codec").field(codec).finish(),
+            VariantSelector::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl FromStr for VariantSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "best" | "source" => VariantSelector::Best,
+            "worst" => VariantSelector::Worst,
+            "first" => VariantSelector::First,
+            lower => match parse_quality(lower) {
+                Some((height, frame_rate)) => VariantSelector::Max {
+                    height: Some(height),
+                    frame_rate,
+                },
+                None => VariantSelector::Name(s.to_string()),
+            },
+        })
+    }
+}
+
+/// Parses qualities like `720p` and `720p60`.
+fn parse_quality(s: &str) -> Option<(usize, Option<f32>)> {
+    let (height, rate) = s.split_once('p')?;
+    let height = height.parse().ok()?;
+    let rate = match rate {
+        "" => None,
+        rate => Some(rate.parse().ok()?),
+    };
+    Some((height, rate))
+}
+
+/// The properties of an alternative rendition (`EXT-X-MEDIA`) that a
+/// [`RenditionSelector`] chooses from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenditionInfo {
+    pub name: String,
+    /// RFC 5646 language tag, e.g. `da` or `en-US`.
+    pub language: Option<String>,
+    /// The rendition is played when the user has not chosen one.
+    pub default: bool,
+}
+
+/// Picks one of the alternative renditions of a variant, such as an audio
+/// track.
+///
+/// Selectors can be parsed from strings, `"default"` picks the default
+/// rendition, two or three letter codes and tags like `"en-US"` are
+/// languages and everything else is taken as a rendition name.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum RenditionSelector {
+    /// The rendition marked as default, or the first one.
+    #[default]
+    Default,
+    /// The first rendition in this language, `"en"` also matches `"en-US"`.
+    Language(String),
+    /// The rendition with this name.
+    Name(String),
+}
+
+impl RenditionSelector {
+    /// Returns the index of the selected rendition, or `None` if no
+    /// rendition matches.
+    pub fn select(&self, renditions: &[RenditionInfo]) -> Option<usize> {
+        match self {
+            RenditionSelector::Default => renditions
+                .iter()
+                .position(|r| r.default)
+                .or((!renditions.is_empty()).then_some(0)),
+            RenditionSelector::Language(wanted) => {
+                let matches = |language: &str| {
+                    language.eq_ignore_ascii_case(wanted)
+                        || language
+                            .split('-')
+                            .next()
+                            .is_some_and(|primary| primary.eq_ignore_ascii_case(wanted))
+                };
+                renditions
+                    .iter()
+                    .position(|r| r.language.as_deref().is_some_and(matches))
+            }
+            RenditionSelector::Name(name) => renditions.iter().position(|r| &r.name == name),
+        }
+    }
+}
+
+impl FromStr for RenditionSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let is_language = {
+            let mut parts = s.split('-');
+            parts.next().is_some_and(|p| {
+                (2..=3).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphabetic())
+            }) && parts.all(|p| {
+                !p.is_empty() && p.len() <= 8 && p.chars().all(|c| c.is_ascii_alphanumeric())
+            })
+        };
+        Ok(if s.eq_ignore_ascii_case("default") {
+            RenditionSelector::Default
+        } else if is_language {
+            RenditionSelector::Language(s.to_string())
+        } else {
+            RenditionSelector::Name(s.to_string())
+        })
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::StreamExt as _;
+use reqwest::Client;
+use stream_lib::{Event, StreamBuilder, VariantSelector};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Serves `manifest()` at `/manifest.mpd` and every other path as its own
+/// name followed by `|`, so the output shows which segments were written.
+async fn serve(manifest: impl Fn() -> String + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request);
+            let path = request.split(' ').nth(1).unwrap_or("/");
+            let body = match path {
+                "/manifest.mpd" => manifest(),
+                path => format!("{}|", path.trim_start_matches('/')),
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+    format!("http://{}/manifest.mpd", addr)
+}
+
+fn download(url: &str) -> stream_lib::DownloadStream {
+    let http = Client::new();
+    let request = http.get(url).build().unwrap();
+    StreamBuilder::new(http, request)
+        .variant(VariantSelector::Best)
+        .dash()
+}
+
+#[tokio::test]
+async fn downloads_static_manifest() {
+    let url = serve(|| {
+        r#"<MPD type="static" mediaPresentationDuration="PT6S"><Period>
+        <AdaptationSet contentType="video">
+        <SegmentTemplate initialization="$RepresentationID$/init.mp4"
+            media="$RepresentationID$/$Number%03d$.m4s" duration="2"/>
+        <Representation id="low" bandwidth="100"/><Representation id="high" bandwidth="200"/>
+        </AdaptationSet></Period></MPD>"#
+            .to_string()
+    })
+    .await;
+
+    let mut output = String::new();
+    let mut segments = Vec::new();
+    let mut dl = download(&url);
+    while let Some(event) = dl.next().await {
+        match event {
+            Event::Bytes { bytes } => output.push_str(std::str::from_utf8(&bytes).unwrap()),
+            Event::SegmentStart { info } => segments.push(info.sequence),
+            Event::End => break,
+            Event::SegmentEnd { .. } => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    assert_eq!(segments, [1, 2, 3]);
+    assert_eq!(
+        output,
+        "high/init.mp4|high/001.m4s|high/002.m4s|high/003.m4s|"
+    );
+}
+
+#[tokio::test]
+async fn follows_dynamic_manifest() {
+    // The presentation started long ago, the recording starts at the live
+    // edge instead of the beginning.
+    let start = DateTime::<Utc>::from(SystemTime::now()) - chrono::Duration::hours(1);
+    let url = serve(move || {
+        format!(
+            r#"<MPD type="dynamic" availabilityStartTime="{}" minimumUpdatePeriod="PT1S">
+            <Period start="PT0S"><AdaptationSet contentType="video">
+            <SegmentTemplate media="$Number$.m4s" startNumber="0" duration="1"/>
+            <Representation id="v" bandwidth="100"/></AdaptationSet></Period></MPD>"#,
+            start.to_rfc3339_opts(SecondsFormat::Millis, true)
+        )
+    })
+    .await;
+
+    let mut segments = Vec::new();
+    let mut dl = download(&url);
+    let events = async {
+        while let Some(event) = dl.next().await {
+            match event {
+                Event::SegmentStart { info } => {
+                    assert!(info.program_date_time.is_some());
+                    segments.push(info.sequence);
+                    if segments.len() == 5 {
+                        break;
+                    }
+                }
+                Event::Bytes { .. } | Event::SegmentEnd { .. } => {}
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(20), events)
+        .await
+        .expect("the live edge did not move");
+
+    // One hour of one second segments.
+    assert!(segments[0] >= 3590, "started at {}", segments[0]);
+    assert!(
+        segments.windows(2).all(|w| w[1] == w[0] + 1),
+        "{:?}",
+        segments
+    );
+}